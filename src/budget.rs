@@ -0,0 +1,198 @@
+//! Status computation within a time budget.
+//!
+//! The status is computed in a worker thread, in stages ordered by their
+//! cost. Once the budget is exhausted, whatever has been computed by then is
+//! returned, leaving the remaining parts of `GitInfo` undetermined.
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+
+use crate::{Dirty, GitInfo, Location, Options, Parts, Status};
+
+enum Stage {
+    /// Everything but the working tree status; at first only `HEAD` and
+    /// its upstream, then everything else.
+    Basic(Box<GitInfo>),
+    /// Whether there are changes, when not counting them.
+    Dirty(Dirty),
+    /// The status, possibly without the untracked file count or line
+    /// counts.
+    Status(Status),
+}
+
+type Message = anyhow::Result<Option<Stage>>;
+
+/// Returns the information for the repository at `location`, or `None` if
+/// there is no such repository.
+pub fn info(
+    location: Location,
+    options: Options,
+    timeout: Duration,
+) -> anyhow::Result<Option<GitInfo>> {
+    let deadline = Instant::now() + timeout;
+    let (tx, rx) = mpsc::channel();
+    // When we return early, the worker is left running until the process
+    // exits; sending to the closed channel is harmless.
+    thread::spawn(move || {
+        let stages = Stages(tx);
+        if let Err(e) = compute(&location, &options, &stages) {
+            stages.fail(e);
+        }
+    });
+    collect(&rx, deadline, timeout)
+}
+
+/// Like `info`, but with the stages computed by `compute`, which is run in a
+/// scoped thread, so it can borrow an already open repository.
+///
+/// Once the budget is exhausted, the information is passed to `respond`;
+/// only then the worker is waited for, and left to finish its computation.
+pub(crate) fn scoped<C, R, T>(timeout: Duration, compute: C, respond: R) -> T
+where
+    C: FnOnce(&Stages) -> anyhow::Result<()> + Send,
+    R: FnOnce(anyhow::Result<GitInfo>) -> T,
+{
+    let deadline = Instant::now() + timeout;
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            let stages = Stages(tx);
+            if let Err(e) = compute(&stages) {
+                stages.fail(e);
+            }
+        });
+        let info = collect(&rx, deadline, timeout)
+            .and_then(|info| info.ok_or_else(|| anyhow!("no repository")));
+        respond(info)
+    })
+}
+
+/// Assembles the information from the stages received until the worker
+/// finishes, or the `deadline` has passed.
+fn collect(
+    rx: &mpsc::Receiver<Message>,
+    deadline: Instant,
+    timeout: Duration,
+) -> anyhow::Result<Option<GitInfo>> {
+    let mut info: Option<GitInfo> = None;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(remaining) {
+            Ok(Ok(Some(Stage::Basic(basic)))) => info = Some(*basic),
+            Ok(Ok(Some(Stage::Dirty(dirty)))) => {
+                if let Some(info) = &mut info {
+                    info.dirty = Some(dirty);
+                }
+            }
+            Ok(Ok(Some(Stage::Status(status)))) => {
+                if let Some(info) = &mut info {
+                    info.dirty = Some(Dirty::from_status(&status));
+                    info.status = Some(status);
+                }
+            }
+            Ok(Ok(None)) => return Ok(None),
+            Ok(Err(e)) => return Err(e),
+            // The worker has finished.
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => match info {
+                Some(_) => break,
+                None => bail!("timed out after {:?}", timeout),
+            },
+        }
+    }
+    info.map(Some)
+        .ok_or_else(|| anyhow!("status computation failed"))
+}
+
+fn compute(location: &Location, options: &Options, stages: &Stages) -> anyhow::Result<()> {
+    let repo = match location.open()? {
+        Some(repo) => repo,
+        None => {
+            let _ = stages.0.send(Ok(None));
+            return Ok(());
+        }
+    };
+    if stages.basic(&repo, options)? {
+        stages.scan(&repo, options)?;
+    }
+    Ok(())
+}
+
+/// The sending end for the stages computed by a worker.
+pub(crate) struct Stages(mpsc::Sender<Message>);
+
+impl Stages {
+    /// Sends `stage`, returning `false` if the budget is exhausted, and
+    /// nobody is listening anymore, so there's no point in continuing then.
+    fn send(&self, stage: Stage) -> bool {
+        self.0.send(Ok(Some(stage))).is_ok()
+    }
+
+    fn fail(&self, e: anyhow::Error) {
+        let _ = self.0.send(Err(e));
+    }
+
+    /// Sends everything but the working tree status, returning whether to
+    /// continue.
+    pub(crate) fn basic(&self, repo: &git2::Repository, options: &Options) -> anyhow::Result<bool> {
+        // Describing a detached `HEAD`, and comparing against other
+        // branches, may take a while, so `HEAD` and its upstream are made
+        // available first.
+        let head_options = Options {
+            parts: Parts {
+                distance: options.parts.distance,
+                ..Parts::NONE
+            },
+            ..options.clone()
+        };
+        let basic = |options| -> anyhow::Result<Stage> {
+            Ok(Stage::Basic(Box::new(GitInfo::without_status(
+                repo, options,
+            )?)))
+        };
+        if !self.send(basic(&head_options)?) {
+            return Ok(false);
+        }
+        Ok(head_options.parts == options.parts || self.send(basic(options)?))
+    }
+
+    /// Scans the working tree for the status, or for whether there are any
+    /// changes, as requested by `options`.
+    pub(crate) fn scan(&self, repo: &git2::Repository, options: &Options) -> anyhow::Result<()> {
+        if !options.counts_files() {
+            if options.parts.dirty {
+                self.send(Stage::Dirty(Dirty::from_repo(repo)?));
+            }
+            return Ok(());
+        }
+        if !self.send(Stage::Status(Status::from_repo_without_untracked(repo)?)) {
+            return Ok(());
+        }
+        self.status(repo, options, Status::from_repo(repo)?)
+    }
+
+    /// Sends the already computed `status`, along with the line counts if
+    /// requested by `options`.
+    pub(crate) fn status(
+        &self,
+        repo: &git2::Repository,
+        options: &Options,
+        status: Status,
+    ) -> anyhow::Result<()> {
+        if !options.counts_files() {
+            if options.parts.dirty {
+                self.send(Stage::Dirty(Dirty::from_status(&status)));
+            }
+            return Ok(());
+        }
+        // Counting lines is the most expensive part, so the status is made
+        // available without them first.
+        let _ = self.send(Stage::Status(status.clone()))
+            && options.counts_lines()
+            && self.send(Stage::Status(status.count_lines(repo)?));
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+//! On-disk cache of the output for unchanged repositories.
+//!
+//! For each repository, the output of the most recent query is stored
+//! along with a fingerprint of the repository: the metadata of the files
+//! that change along with the index, `HEAD`, the relevant branches and the
+//! configuration, and of the tracked files and the directories containing
+//! them. As long as these match, the stored output is returned without
+//! comparing file contents, or looking for untracked files.
+//!
+//! Untracked files are only noticed by the change to their directory, so
+//! files added within untracked directories, or modifications to untracked
+//! ignore files, go unnoticed; this is why the cache needs to be enabled
+//! explicitly.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{budget, push, Format, GitInfo, Location, Options};
+
+/// A cached output, along with what it was computed from.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    options: Options,
+    format: String,
+    fingerprint: Vec<(PathBuf, Option<Stamp>)>,
+    /// A hash of the stamps of the tracked files and their directories.
+    worktree: u64,
+    output: String,
+}
+
+/// The metadata of a file that changes when it is written or replaced;
+/// `None` in a fingerprint if there is no such file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct Stamp {
+    mtime: i64,
+    mtime_nsec: i64,
+    size: u64,
+    ino: u64,
+}
+
+/// Returns the default cache location, in the per-user runtime directory.
+pub fn default_dir() -> anyhow::Result<PathBuf> {
+    Ok(crate::runtime_dir()?.join("cache"))
+}
+
+/// Returns the output for the repository containing `dir`, rendered
+/// according to `format`, or `None` if there is no such repository.
+///
+/// The output is taken from the cache in `cache_dir` if the repository
+/// has not changed since it was stored there; otherwise, it is computed and
+/// stored, unless it is incomplete due to `options.timeout`. As with the
+/// runtime directory, `cache_dir` is refused unless it is private to the
+/// current user, as anyone able to write there could forge the output.
+pub fn query(
+    cache_dir: &Path,
+    dir: &Path,
+    options: &Options,
+    format: &str,
+) -> anyhow::Result<Option<String>> {
+    let location = Location::new(dir, options);
+    let repo = match location.open()? {
+        Some(repo) => repo,
+        None => return Ok(None),
+    };
+    let mut entry = Entry {
+        options: options.clone(),
+        format: format.into(),
+        // Taken before computing the output, so that changes made in the
+        // meantime invalidate it.
+        fingerprint: fingerprint(&repo, options)?,
+        worktree: worktree_hash(&repo)?,
+        output: String::new(),
+    };
+    crate::private_dir(cache_dir)?;
+    let path = cache_dir.join(file_name(&repo));
+    if let Some(cached) = load(&path) {
+        if cached.options == entry.options
+            && cached.format == entry.format
+            && cached.fingerprint == entry.fingerprint
+            && cached.worktree == entry.worktree
+        {
+            return Ok(Some(cached.output));
+        }
+    }
+    let info = match options.timeout {
+        Some(timeout) => match budget::info(location, options.clone(), timeout)? {
+            Some(info) => info,
+            None => return Ok(None),
+        },
+        None => GitInfo::from_repo(&repo, options)?,
+    };
+    entry.output = Format::parse(format)?.render(&info)?;
+    if is_complete(&info, options) {
+        // Failing to cache the output is no reason to withhold it.
+        let _ = store(&path, &entry);
+    }
+    Ok(Some(entry.output))
+}
+
+/// Checks whether everything requested by `options` was determined.
+fn is_complete(info: &GitInfo, options: &Options) -> bool {
+    if options.counts_files() {
+        match &info.status {
+            Some(status) => {
+                status.untracked.is_some()
+                    && (!options.counts_lines() || status.index.insertions.is_some())
+            }
+            None => false,
+        }
+    } else {
+        !options.parts.dirty || info.dirty.is_some()
+    }
+}
+
+/// Returns the name of the cache file for `repo`.
+fn file_name(repo: &git2::Repository) -> String {
+    let mut hasher = DefaultHasher::new();
+    repo.path().hash(&mut hasher);
+    repo.workdir().hash(&mut hasher);
+    format!("{:016x}.json", hasher.finish())
+}
+
+fn load(path: &Path) -> Option<Entry> {
+    let contents = fs::read(path).ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+/// Writes `entry` to `path`, replacing it atomically, so that concurrent
+/// queries never see a partially written file.
+fn store(path: &Path, entry: &Entry) -> anyhow::Result<()> {
+    let temp = path.with_extension(format!("{}.tmp", process::id()));
+    fs::write(&temp, serde_json::to_vec(entry)?)?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+/// Collects the metadata of the files in the git directory whose changes
+/// may change the output.
+fn fingerprint(
+    repo: &git2::Repository,
+    options: &Options,
+) -> anyhow::Result<Vec<(PathBuf, Option<Stamp>)>> {
+    let git_dir = repo.path();
+    let common_dir = crate::common_dir(git_dir)?;
+    // The git directory itself changes when files such as `MERGE_HEAD` are
+    // created or removed, or replaced like `HEAD` and the index are.
+    let mut paths = vec![
+        git_dir.to_path_buf(),
+        git_dir.join("index"),
+        git_dir.join("HEAD"),
+        common_dir.join("config"),
+        common_dir.join("info").join("exclude"),
+        common_dir.join("packed-refs"),
+        common_dir.join("logs").join("refs").join("stash"),
+    ];
+    let mut refs = Vec::new();
+    let head = repo.find_reference("HEAD")?;
+    if let Some(branch) = head.symbolic_target() {
+        refs.push(branch.to_string());
+        match repo.branch_upstream_name(branch) {
+            Ok(upstream) => refs.extend(upstream.as_str().map(String::from)),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        refs.extend(push::tracking_ref(repo, branch)?);
+    }
+    for candidate in crate::default_branch_candidates(repo, &options.default_branches)? {
+        if let Ok(reference) = repo.find_reference(&candidate) {
+            refs.extend(reference.symbolic_target().map(String::from));
+        }
+        refs.push(candidate);
+    }
+    paths.extend(refs.iter().map(|refname| common_dir.join(refname)));
+    paths
+        .into_iter()
+        .map(|path| Ok((path.clone(), stamp(&path)?)))
+        .collect()
+}
+
+/// Hashes the metadata of the tracked files, which changes when they are
+/// modified, and of the directories containing them, which changes when
+/// files are added to or removed from them.
+///
+/// Like `git status`, this has to look at every tracked file, but nothing
+/// more, which is far cheaper than comparing their contents, or looking for
+/// untracked files.
+fn worktree_hash(repo: &git2::Repository) -> anyhow::Result<u64> {
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir,
+        None => return Ok(0),
+    };
+    let index = repo.index()?;
+    let mut hasher = DefaultHasher::new();
+    let mut dirs = BTreeSet::new();
+    dirs.insert(Vec::new());
+    for entry in index.iter() {
+        if let Some(end) = entry.path.iter().rposition(|&c| c == b'/') {
+            dirs.insert(entry.path[..end].to_vec());
+        }
+        stamp(&workdir.join(OsStr::from_bytes(&entry.path)))?.hash(&mut hasher);
+    }
+    for dir in dirs {
+        stamp(&workdir.join(OsStr::from_bytes(&dir)))?.hash(&mut hasher);
+    }
+    Ok(hasher.finish())
+}
+
+/// Returns the metadata of `path`, without following symlinks.
+fn stamp(path: &Path) -> anyhow::Result<Option<Stamp>> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => Ok(Some(Stamp {
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            size: metadata.size(),
+            ino: metadata.ino(),
+        })),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::os::unix::fs::DirBuilderExt;
+
+    use tempfile::TempDir;
+
+    use crate::test_repo::TestRepo;
+
+    /// A repository with a single commit containing the file `f`, and a
+    /// cache directory.
+    struct Fixture {
+        repo: TestRepo,
+        cache_dir: TempDir,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            Fixture {
+                repo: TestRepo::with_files(&["f"]),
+                cache_dir: TempDir::new().unwrap(),
+            }
+        }
+
+        fn query(&self, format: &str) -> String {
+            let options = Options {
+                parts: Format::parse(format).unwrap().parts(),
+                ..Options::default()
+            };
+            query(&self.cache_dir(), self.repo.dir.path(), &options, format)
+                .unwrap()
+                .unwrap()
+        }
+
+        /// Returns the cache directory, which is created by `query`, as the
+        /// temporary directory itself may be accessible to others.
+        fn cache_dir(&self) -> PathBuf {
+            self.cache_dir.path().join("cache")
+        }
+
+        /// Checks the output, both freshly computed and from the cache.
+        fn check(&self, format: &str, expected: &str) {
+            assert_eq!(self.query(format), expected);
+            let cached = fs::read_dir(self.cache_dir()).unwrap().count();
+            assert_eq!(cached, 1);
+            assert_eq!(self.query(format), expected, "from the cache");
+        }
+    }
+
+    #[test]
+    fn modified_in_place() {
+        let fixture = Fixture::new();
+        fixture.check("{changed}", "0");
+        fs::OpenOptions::new()
+            .append(true)
+            .open(fixture.repo.path("f"))
+            .unwrap()
+            .write_all(b"appended\n")
+            .unwrap();
+        fixture.check("{changed}", "1");
+    }
+
+    #[test]
+    fn default_branch_moved() {
+        let fixture = Fixture::new();
+        let repo = &fixture.repo.repo;
+        let head = repo.head().unwrap().target().unwrap();
+        let origin = "refs/remotes/origin/main";
+        repo.reference(origin, head, true, "").unwrap();
+        fixture.check("{default_behind}", "0");
+        let fetched = fixture.repo.commit_to(None, "fetched");
+        repo.reference(origin, fetched, true, "").unwrap();
+        fixture.check("{default_behind}", "1");
+    }
+
+    #[test]
+    fn shared_cache_dir() {
+        let fixture = Fixture::new();
+        fs::DirBuilder::new()
+            .mode(0o755)
+            .create(fixture.cache_dir())
+            .unwrap();
+        let options = Options::default();
+        let result = query(&fixture.cache_dir(), fixture.repo.dir.path(), &options, "");
+        assert!(result.is_err());
+    }
+}
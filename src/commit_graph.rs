@@ -0,0 +1,158 @@
+//! Generation numbers from git's commit-graph files.
+//!
+//! Written by `git commit-graph write`, and by `git gc` or `git maintenance`
+//! as configured, these files record the generation number of each commit:
+//! one more than the maximum among its parents. Walking commits in order of
+//! decreasing generation visits each commit after all its descendants,
+//! regardless of clock skew.
+//!
+//! The files can be large, so they are not read as a whole; the commit ids
+//! are looked up by binary search instead.
+
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+const SIGNATURE: &[u8] = b"CGPH";
+const OID_FANOUT: &[u8] = b"OIDF";
+const OID_LOOKUP: &[u8] = b"OIDL";
+const COMMIT_DATA: &[u8] = b"CDAT";
+
+/// Length of a SHA-1 commit id, the only kind supported by libgit2.
+const OID_LEN: u64 = 20;
+
+/// The commit-graph of a repository, possibly split into several layers.
+pub struct CommitGraph {
+    layers: Vec<Layer>,
+}
+
+/// A single commit-graph file.
+struct Layer {
+    file: File,
+    fanout: Vec<u32>,
+    lookup_offset: u64,
+    data_offset: u64,
+}
+
+impl CommitGraph {
+    /// Opens the commit-graph of `repo`, returning `None` if there is none
+    /// that can be used, or if its use is disabled by `core.commitGraph`.
+    pub fn open(repo: &git2::Repository) -> anyhow::Result<Option<Self>> {
+        match repo.config()?.get_bool("core.commitGraph") {
+            Ok(false) => return Ok(None),
+            Ok(true) => {}
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let info = crate::common_dir(repo.path())?.join("objects").join("info");
+        let chain = info.join("commit-graphs");
+        let paths = match fs::read_to_string(chain.join("commit-graph-chain")) {
+            Ok(hashes) => hashes
+                .lines()
+                .map(|hash| chain.join(format!("graph-{}.graph", hash)))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![info.join("commit-graph")],
+            Err(e) => return Err(e.into()),
+        };
+        let mut layers = Vec::with_capacity(paths.len());
+        for path in paths {
+            match Layer::open(&path)? {
+                Some(layer) => layers.push(layer),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(CommitGraph { layers }))
+    }
+
+    /// Returns the generation number of the commit `oid`, or `None` if it
+    /// is not in the graph, or its generation number was not computed.
+    pub fn generation(&self, oid: git2::Oid) -> anyhow::Result<Option<u32>> {
+        for layer in &self.layers {
+            if let Some(generation) = layer.generation(oid)? {
+                return Ok(Some(generation));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Layer {
+    /// Opens the commit-graph file at `path`, returning `None` if it does
+    /// not exist or is not understood.
+    fn open(path: &Path) -> anyhow::Result<Option<Self>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut header = [0; 8];
+        file.read_exact_at(&mut header, 0)?;
+        // Besides the signature, check the version and the hash algorithm.
+        if &header[..4] != SIGNATURE || header[4] != 1 || header[5] != 1 {
+            return Ok(None);
+        }
+        let chunks = usize::from(header[6]);
+        let mut table = vec![0; (chunks + 1) * 12];
+        file.read_exact_at(&mut table, 8)?;
+        let chunk = |id: &[u8]| {
+            table
+                .chunks(12)
+                .find(|entry| &entry[..4] == id)
+                .map(|entry| u64::from_be_bytes(entry[4..].try_into().unwrap()))
+        };
+        let (fanout_offset, lookup_offset, data_offset) =
+            match (chunk(OID_FANOUT), chunk(OID_LOOKUP), chunk(COMMIT_DATA)) {
+                (Some(fanout), Some(lookup), Some(data)) => (fanout, lookup, data),
+                _ => return Ok(None),
+            };
+        let mut fanout = vec![0; 256 * 4];
+        file.read_exact_at(&mut fanout, fanout_offset)?;
+        let fanout = fanout
+            .chunks(4)
+            .map(|count| u32::from_be_bytes(count.try_into().unwrap()))
+            .collect();
+        Ok(Some(Layer {
+            file,
+            fanout,
+            lookup_offset,
+            data_offset,
+        }))
+    }
+
+    fn generation(&self, oid: git2::Oid) -> anyhow::Result<Option<u32>> {
+        let first = oid.as_bytes()[0];
+        let mut low = match first {
+            0 => 0,
+            _ => self.fanout[usize::from(first) - 1],
+        };
+        let mut high = self.fanout[usize::from(first)];
+        let mut candidate = [0; OID_LEN as usize];
+        while low < high {
+            let mid = low + (high - low) / 2;
+            self.file.read_exact_at(
+                &mut candidate,
+                self.lookup_offset + u64::from(mid) * OID_LEN,
+            )?;
+            match candidate[..].cmp(oid.as_bytes()) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => {
+                    // The commit data consists of the tree id, two parent
+                    // positions, and the generation number in the upper 30
+                    // bits of the following 32.
+                    let mut generation = [0; 4];
+                    let record = self.data_offset + u64::from(mid) * (OID_LEN + 16);
+                    self.file
+                        .read_exact_at(&mut generation, record + OID_LEN + 8)?;
+                    // Old versions of git wrote zero, for not computed.
+                    let generation = u32::from_be_bytes(generation) >> 2;
+                    return Ok(Some(generation).filter(|&generation| generation != 0));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
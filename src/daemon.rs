@@ -0,0 +1,310 @@
+//! Resident status daemon and its client.
+//!
+//! The daemon listens on a Unix domain socket and keeps repositories open
+//! across requests. The protocol is line-based: the client sends a single
+//! JSON-encoded `Request` line, and the daemon answers with a single
+//! JSON-encoded `Response` line, after which the connection is closed.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::budget::{self, Stages};
+#[cfg(target_os = "linux")]
+use crate::watch::Watcher;
+use crate::{Format, GitInfo, Location, Options, Status};
+
+/// How long to wait for a client to send its request, or accept the
+/// response, before giving up on it.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many repositories are kept open, along with their watches; the
+/// least recently queried one is closed to make room for another.
+const MAX_REPOS: usize = 16;
+
+/// How long a repository is kept open without being queried.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// How long a client waits for the answer without `Options::timeout`,
+/// before giving up on the daemon.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How much longer than `Options::timeout` a client waits for the answer,
+/// allowing for the time it takes to pass on the request and the response.
+const QUERY_SLACK: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Request {
+    /// The directory to query the status for.
+    dir: PathBuf,
+    /// How to find the repository, and what to compute.
+    #[serde(flatten)]
+    options: Options,
+    /// Output format specification, as passed to `--format`.
+    format: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Response {
+    /// The rendered output; empty if there is no repository.
+    Output(String),
+    Error(String),
+}
+
+/// Returns the default socket location, in the per-user runtime directory.
+pub fn default_socket_path() -> anyhow::Result<PathBuf> {
+    Ok(crate::runtime_dir()?.join("socket"))
+}
+
+/// Queries the daemon listening on `socket` for the status of the
+/// repository containing `dir`, rendered according to `format`.
+///
+/// Returns `Ok(None)` if no daemon is listening, or if it does not answer
+/// shortly after `options.timeout` (or within two seconds), so the caller
+/// can fall back to computing the status itself. Relative paths are
+/// resolved by the daemon, so they should be made absolute beforehand.
+pub fn query(
+    socket: &Path,
+    dir: &Path,
+    options: &Options,
+    format: &str,
+) -> anyhow::Result<Option<String>> {
+    let mut stream = match UnixStream::connect(socket) {
+        Ok(stream) => stream,
+        Err(e) if is_not_listening(&e) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let timeout = options
+        .timeout
+        .map_or(QUERY_TIMEOUT, |timeout| timeout + QUERY_SLACK);
+    stream.set_read_timeout(Some(timeout))?;
+    let request = Request {
+        dir: dir.to_path_buf(),
+        options: options.clone(),
+        format: format.into(),
+    };
+    serde_json::to_writer(&mut stream, &request)?;
+    stream.write_all(b"\n")?;
+    let mut line = String::new();
+    match BufReader::new(stream).read_line(&mut line) {
+        Ok(_) => {}
+        Err(e) if is_timeout(&e) => return Ok(None),
+        Err(e) => return Err(anyhow::Error::new(e).context("no response from daemon")),
+    }
+    match serde_json::from_str(&line).context("invalid response from daemon")? {
+        Response::Output(output) => Ok(Some(output)),
+        Response::Error(e) => Err(anyhow!("{}", e)),
+    }
+}
+
+fn is_not_listening(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+    )
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Runs the daemon, serving requests on `socket` until killed.
+pub fn serve(socket: &Path) -> anyhow::Result<()> {
+    if let Some(dir) = socket.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+    }
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            bail!("a daemon is already listening on {}", socket.display());
+        }
+        fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("could not bind to {}", socket.display()))?;
+    let mut daemon = Daemon::default();
+    for stream in listener.incoming() {
+        // Failing connections must not bring down the daemon.
+        let _ = stream.map_err(Into::into).and_then(|s| daemon.handle(s));
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Daemon {
+    /// Open repositories, keyed by the path of their git directory and
+    /// working tree override.
+    repos: HashMap<(PathBuf, Option<PathBuf>), Repo>,
+}
+
+struct Repo {
+    repo: git2::Repository,
+    /// When the repository was last queried.
+    last_used: Instant,
+    /// Tracks changes for incremental status computation, if possible.
+    #[cfg(target_os = "linux")]
+    watcher: Option<Watcher>,
+}
+
+impl Repo {
+    fn open(git_dir: &Path, work_tree: Option<&Path>) -> anyhow::Result<Self> {
+        let repo = git2::Repository::open(git_dir)?;
+        if let Some(work_tree) = work_tree {
+            repo.set_workdir(work_tree, false)?;
+        }
+        Ok(Repo {
+            last_used: Instant::now(),
+            // Watching fails, for instance, when running into the inotify
+            // watch limit; we can still do full scans then.
+            #[cfg(target_os = "linux")]
+            watcher: Watcher::new(&repo).ok().flatten(),
+            repo,
+        })
+    }
+
+    /// Returns the working tree status from the watcher, if there is one,
+    /// and the status is requested by `options` at all.
+    fn watched_status(&mut self, options: &Options) -> Option<Status> {
+        if !(options.parts.status || options.parts.dirty) {
+            return None;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(watcher) = &mut self.watcher {
+            match watcher.status(&self.repo) {
+                Ok(status) => return Some(status),
+                Err(_) => self.watcher = None,
+            }
+        }
+        None
+    }
+
+    fn info(&mut self, options: &Options) -> anyhow::Result<GitInfo> {
+        match self.watched_status(options) {
+            Some(status) => GitInfo::with_status(&self.repo, status, options),
+            None => GitInfo::from_repo(&self.repo, options),
+        }
+    }
+
+    /// Like `info`, but sends the information in stages, for use within
+    /// a time budget.
+    fn send_info(&mut self, options: &Options, stages: &Stages) -> anyhow::Result<()> {
+        if !stages.basic(&self.repo, options)? {
+            return Ok(());
+        }
+        match self.watched_status(options) {
+            Some(status) => stages.status(&self.repo, options, status),
+            None => stages.scan(&self.repo, options),
+        }
+    }
+}
+
+impl Daemon {
+    fn handle(&mut self, stream: UnixStream) -> anyhow::Result<()> {
+        // Requests are served one at a time, so a stalled client must not
+        // hold up the others.
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        self.respond(&line, |output| {
+            let response = match output {
+                Ok(output) => Response::Output(output),
+                Err(e) => Response::Error(e.to_string()),
+            };
+            let mut writer = &stream;
+            serde_json::to_writer(&mut writer, &response)?;
+            writer.write_all(b"\n")?;
+            Ok(())
+        })
+    }
+
+    /// Computes the output for the request `line`, and passes it to
+    /// `respond`.
+    ///
+    /// With `Options::timeout`, the output is passed on once the timeout is
+    /// exhausted, and the status computation is finished afterwards.
+    fn respond<R>(&mut self, line: &str, respond: R) -> anyhow::Result<()>
+    where
+        R: FnOnce(anyhow::Result<String>) -> anyhow::Result<()>,
+    {
+        let (request, format, repo) = match self.request(line) {
+            Ok(Some(request)) => request,
+            Ok(None) => return respond(Ok(String::new())),
+            Err(e) => return respond(Err(e)),
+        };
+        let options = &request.options;
+        let render = |info: anyhow::Result<GitInfo>| info.and_then(|info| format.render(&info));
+        match options.timeout {
+            Some(timeout) => budget::scoped(
+                timeout,
+                |stages| repo.send_info(options, stages),
+                |info| respond(render(info)),
+            ),
+            None => respond(render(repo.info(options))),
+        }
+    }
+
+    /// Parses the request `line`, returning it along with its output format
+    /// and repository, or `None` if there is no repository.
+    fn request(&mut self, line: &str) -> anyhow::Result<Option<(Request, Format, &mut Repo)>> {
+        let request: Request = serde_json::from_str(line).context("invalid request")?;
+        let format = Format::parse(&request.format)?;
+        let location = Location::new(&request.dir, &request.options);
+        Ok(self.repo(&location)?.map(|repo| (request, format, repo)))
+    }
+
+    /// Returns the repository at `location`, opening it if necessary.
+    ///
+    /// Repositories that were removed, or have not been queried for a while,
+    /// are closed first.
+    fn repo(&mut self, location: &Location) -> anyhow::Result<Option<&mut Repo>> {
+        self.repos.retain(|(git_dir, _), repo| {
+            git_dir.exists() && repo.last_used.elapsed() < IDLE_TIMEOUT
+        });
+        let git_dir = match &location.git_dir {
+            Some(git_dir) => git_dir.clone(),
+            None => match git2::Repository::discover_path(&location.dir, None::<&str>) {
+                Ok(git_dir) => git_dir,
+                Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            },
+        };
+        let key = (git_dir, location.work_tree.clone());
+        // Closing a repository first frees its watches for the new one.
+        if !self.repos.contains_key(&key) && self.repos.len() >= MAX_REPOS {
+            let least_recent = self
+                .repos
+                .iter()
+                .min_by_key(|(_, repo)| repo.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(least_recent) = least_recent {
+                self.repos.remove(&least_recent);
+            }
+        }
+        let repo = match self.repos.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let (git_dir, work_tree) = entry.key();
+                let repo = Repo::open(git_dir, work_tree.as_deref())?;
+                entry.insert(repo)
+            }
+        };
+        repo.last_used = Instant::now();
+        Ok(Some(repo))
+    }
+}
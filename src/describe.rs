@@ -0,0 +1,112 @@
+//! Human-friendly names for a detached `HEAD`.
+//!
+//! The following are tried in order:
+//!
+//! 1. A tag pointing at the commit, e.g. `v1.2`.
+//! 2. A remote-tracking or local branch pointing at the commit, e.g.
+//!    `origin/main`.
+//! 3. The most recent tag reachable from the commit, as done by `git
+//!    describe --tags`, e.g. `v1.2-3-gabc1234`.
+//! 4. A branch the commit is a first-parent ancestor of, close to its tip,
+//!    like `git name-rev` does, e.g. `main~4`.
+
+/// Maximum number of first-parent generations to go back from each branch.
+const MAX_GENERATIONS: usize = 100;
+
+/// Maximum number of commits to look at in total when searching branches.
+const MAX_COMMITS: usize = 10_000;
+
+/// Returns a name for the commit `oid`, or `None` if none could be found.
+///
+/// Commit ids in the output of `git describe` are abbreviated to at least
+/// `abbrev` hex digits.
+pub fn describe(
+    repo: &git2::Repository,
+    oid: git2::Oid,
+    abbrev: usize,
+) -> anyhow::Result<Option<String>> {
+    let object = repo.find_object(oid, None)?;
+    if let Some(tag) = describe_tags(&object, true, abbrev)? {
+        return Ok(Some(tag));
+    }
+    let branches = branches(repo)?;
+    if let Some((name, _)) = branches.iter().find(|(_, tip)| *tip == oid) {
+        return Ok(Some(name.clone()));
+    }
+    if let Some(description) = describe_tags(&object, false, abbrev)? {
+        return Ok(Some(description));
+    }
+    name_rev(repo, oid, &branches)
+}
+
+fn describe_tags(
+    object: &git2::Object,
+    exact: bool,
+    abbrev: usize,
+) -> anyhow::Result<Option<String>> {
+    let mut options = git2::DescribeOptions::new();
+    options.describe_tags();
+    if exact {
+        options.max_candidates_tags(0);
+    }
+    match object.describe(&options) {
+        Ok(description) => {
+            let mut format = git2::DescribeFormatOptions::new();
+            format.abbreviated_size(abbrev as u32);
+            Ok(Some(description.format(Some(&format))?))
+        }
+        // Failing to find a suitable tag is reported as a generic error.
+        Err(e) if e.class() == git2::ErrorClass::Describe => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the short names and tips of all remote-tracking branches,
+/// followed by the local branches, each sorted by name.
+fn branches(repo: &git2::Repository) -> anyhow::Result<Vec<(String, git2::Oid)>> {
+    let mut all = Vec::new();
+    for glob in &["refs/remotes/*", "refs/heads/*"] {
+        let mut branches = Vec::new();
+        for reference in repo.references_glob(glob)? {
+            let reference = reference?;
+            // This skips symbolic references, such as `origin/HEAD`.
+            if let (Some(name), Some(oid)) = (reference.shorthand(), reference.target()) {
+                branches.push((name.to_string(), oid));
+            }
+        }
+        branches.sort();
+        all.extend(branches);
+    }
+    Ok(all)
+}
+
+/// Finds the branch that has `oid` as first-parent ancestor the least
+/// number of generations back from its tip.
+fn name_rev(
+    repo: &git2::Repository,
+    oid: git2::Oid,
+    branches: &[(String, git2::Oid)],
+) -> anyhow::Result<Option<String>> {
+    let mut best: Option<(usize, &str)> = None;
+    let mut visited = 0;
+    'branches: for (name, tip) in branches {
+        let limit = best.map_or(MAX_GENERATIONS + 1, |(generation, _)| generation);
+        let mut commit = repo.find_commit(*tip)?;
+        for generation in 1..limit {
+            commit = match commit.parent(0) {
+                Ok(parent) => parent,
+                Err(e) if e.code() == git2::ErrorCode::NotFound => break,
+                Err(e) => return Err(e.into()),
+            };
+            if commit.id() == oid {
+                best = Some((generation, name));
+                break;
+            }
+            visited += 1;
+            if visited >= MAX_COMMITS {
+                break 'branches;
+            }
+        }
+    }
+    Ok(best.map(|(generation, name)| format!("{}~{}", name, generation)))
+}
@@ -0,0 +1,228 @@
+//! Checking for changes without counting them.
+//!
+//! Each question is answered by a scan of its own, which stops at the
+//! first change found, so a repository with many changes is no more
+//! expensive to check than one with few.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::status::Settings;
+use crate::Status;
+
+/// The bits of `git2::IndexEntry::flags` holding the stage of a conflict
+/// entry.
+const STAGE_MASK: u16 = 0x3000;
+
+/// Whether there are changes in the index and working tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dirty {
+    /// Whether there are staged changes or merge conflicts.
+    pub index: bool,
+    /// Whether there are unstaged changes.
+    pub worktree: bool,
+    /// Whether there are untracked files, `None` if not determined.
+    pub untracked: Option<bool>,
+}
+
+impl Dirty {
+    /// Checks the repository for changes.
+    pub fn from_repo(repo: &git2::Repository) -> anyhow::Result<Self> {
+        let settings = Settings::from_repo(repo)?;
+        let mut index = repo.index()?;
+        // The index may have been changed since the repository was opened.
+        index.read(false)?;
+        let workdir = match repo.workdir() {
+            Some(workdir) => workdir,
+            None => {
+                return Ok(Dirty {
+                    index: index_dirty(repo, &index)?,
+                    worktree: false,
+                    untracked: Some(false),
+                })
+            }
+        };
+        Ok(Dirty {
+            index: index_dirty(repo, &index)?,
+            worktree: worktree_dirty(repo, &settings, &index, workdir)?,
+            untracked: Some(
+                settings.show_untracked() && has_untracked(repo, &index, workdir, b"")?,
+            ),
+        })
+    }
+
+    /// Derives the answers from the counts in `status`.
+    pub fn from_status(status: &Status) -> Self {
+        Dirty {
+            index: status.staged > 0 || status.conflicts > 0,
+            worktree: status.changed > 0,
+            untracked: status.untracked.map(|n| n > 0),
+        }
+    }
+
+    /// Whether there are changes of any kind; `None` if that depends on the
+    /// undetermined presence of untracked files.
+    pub fn any(&self) -> Option<bool> {
+        match self.untracked {
+            _ if self.index || self.worktree => Some(true),
+            untracked => untracked,
+        }
+    }
+}
+
+/// Checks whether the index differs from the tree of `HEAD`.
+fn index_dirty(repo: &git2::Repository, index: &git2::Index) -> anyhow::Result<bool> {
+    if index.has_conflicts() {
+        return Ok(true);
+    }
+    let head = match repo.head() {
+        Ok(head) => head.peel_to_tree()?,
+        Err(e) if e.code() == git2::ErrorCode::UnbornBranch => return Ok(!index.is_empty()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = index.iter();
+    let mut path = Vec::new();
+    Ok(tree_differs(repo, &head, &mut path, &mut entries)? || entries.next().is_some())
+}
+
+/// Compares the entries of `tree`, which is at `path`, against the next
+/// ones from `entries`.
+///
+/// The entries of a tree are sorted like the index, taking directories to
+/// end in a slash, so they can be compared in a single pass.
+fn tree_differs(
+    repo: &git2::Repository,
+    tree: &git2::Tree,
+    path: &mut Vec<u8>,
+    entries: &mut git2::IndexEntries,
+) -> anyhow::Result<bool> {
+    for entry in tree.iter() {
+        let len = path.len();
+        path.extend_from_slice(entry.name_bytes());
+        if entry.kind() == Some(git2::ObjectType::Tree) {
+            path.push(b'/');
+            let subtree = repo.find_tree(entry.id())?;
+            if tree_differs(repo, &subtree, path, entries)? {
+                return Ok(true);
+            }
+        } else {
+            match entries.next() {
+                Some(index_entry)
+                    if index_entry.path == *path
+                        && index_entry.id == entry.id()
+                        && index_entry.mode == entry.filemode() as u32 => {}
+                _ => return Ok(true),
+            }
+        }
+        path.truncate(len);
+    }
+    Ok(false)
+}
+
+/// Checks whether any file in the working tree differs from the index.
+///
+/// Files whose metadata matches the index are taken to be unchanged, like
+/// git does; the others are left to libgit2 to compare.
+fn worktree_dirty(
+    repo: &git2::Repository,
+    settings: &Settings,
+    index: &git2::Index,
+    workdir: &Path,
+) -> anyhow::Result<bool> {
+    // Files modified no earlier than the index was written may have been
+    // modified again after being staged, within the timestamp granularity.
+    // A new repository has no index file until something is staged.
+    let index_mtime = match index.path().map(fs::metadata) {
+        Some(Ok(metadata)) => metadata.modified()?,
+        Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => UNIX_EPOCH,
+    };
+    for entry in index.iter() {
+        // Conflicts are not considered unstaged changes.
+        if entry.flags & STAGE_MASK != 0 {
+            continue;
+        }
+        let path = Path::new(OsStr::from_bytes(&entry.path));
+        if entry.mode == u32::from(git2::FileMode::Commit) {
+            match path.to_str() {
+                Some(path) if settings.submodule_modified(repo, path)? => return Ok(true),
+                _ => continue,
+            }
+        }
+        let metadata = match fs::symlink_metadata(workdir.join(path)) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e.into()),
+        };
+        let modified = metadata.modified()?;
+        if modified < index_mtime && stat_matches(&entry, &metadata, modified) {
+            continue;
+        }
+        let changed = {
+            use git2::Status as S;
+            S::WT_MODIFIED | S::WT_DELETED | S::WT_TYPECHANGE | S::WT_RENAMED
+        };
+        if repo.status_file(path)?.intersects(changed) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Compares the metadata recorded in the index with that of the file.
+fn stat_matches(entry: &git2::IndexEntry, metadata: &fs::Metadata, modified: SystemTime) -> bool {
+    let mtime = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let is_symlink = entry.mode == u32::from(git2::FileMode::Link);
+    // The index only records the lower 32 bits of these.
+    entry.file_size == metadata.len() as u32
+        && entry.mtime.seconds() as u32 == mtime.as_secs() as u32
+        && entry.mtime.nanoseconds() == mtime.subsec_nanos()
+        && is_symlink == metadata.file_type().is_symlink()
+}
+
+/// Checks whether the directory at `dir`, relative to `workdir`, contains
+/// untracked files that aren't ignored.
+///
+/// `dir` is empty for the root, and ends in a slash otherwise.
+fn has_untracked(
+    repo: &git2::Repository,
+    index: &git2::Index,
+    workdir: &Path,
+    dir: &[u8],
+) -> anyhow::Result<bool> {
+    for entry in fs::read_dir(workdir.join(OsStr::from_bytes(dir)))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == ".git" {
+            continue;
+        }
+        let mut path = dir.to_vec();
+        path.extend_from_slice(name.as_bytes());
+        let relative = Path::new(OsStr::from_bytes(&path));
+        let is_tracked = (0..=3).any(|stage| index.get_path(relative, stage).is_some());
+        if is_tracked {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            path.push(b'/');
+            let relative = Path::new(OsStr::from_bytes(&path));
+            if repo.is_path_ignored(relative)? {
+                continue;
+            }
+            // A nested repository is reported as a whole, like an untracked
+            // directory.
+            if workdir.join(relative).join(".git").exists()
+                || has_untracked(repo, index, workdir, &path)?
+            {
+                return Ok(true);
+            }
+        } else if !repo.is_path_ignored(relative)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,483 @@
+//! Counting the commits by which two branches have diverged, within limits.
+//!
+//! Like `git2::Repository::graph_ahead_behind`, this walks the commits
+//! reachable from either side, newest first, marking which sides each is
+//! reachable from, until only commits reachable from both sides are left.
+//! With a limit, the walk stops early once the counts on both sides are
+//! known, or known to exceed the limit, and after a number of steps
+//! proportional to the limit at the latest, which bounds the time taken for
+//! branches that diverged long ago. When it runs out of steps, typically
+//! because only one side exceeds the limit, the other side's count is left
+//! unknown.
+//!
+//! Commits are visited after their descendants as long as their commit
+//! times are in order, or always when generation numbers are available from
+//! a commit-graph. When that fails, the counts are left to
+//! `graph_ahead_behind`.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use crate::commit_graph::CommitGraph;
+use crate::Distance;
+
+const LOCAL: u8 = 1;
+const OTHER: u8 = 2;
+
+/// How many commits the walk may visit, as a multiple of the limit.
+const STEPS_PER_LIMIT: usize = 4;
+
+/// Determines distances between commits.
+pub struct Walker {
+    limit: Option<usize>,
+    graph: Option<CommitGraph>,
+}
+
+/// A commit waiting to be visited.
+#[derive(PartialEq, Eq)]
+struct Entry {
+    /// The generation number, or `u32::MAX` if not known.
+    generation: u32,
+    time: i64,
+    oid: git2::Oid,
+}
+
+/// The position of a commit in the walk: generation number and commit time.
+type Key = (u32, i64);
+
+impl Entry {
+    fn key(&self) -> Key {
+        (self.generation, self.time)
+    }
+}
+
+impl Ord for Entry {
+    /// Orders by generation number, falling back to the commit time, so
+    /// that the heap yields descendants before their ancestors.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.key(), self.oid).cmp(&(other.key(), other.oid))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The state of a walk.
+struct Walk<'a> {
+    walker: &'a Walker,
+    repo: &'a git2::Repository,
+    /// The sides each commit seen so far is known to be reachable from.
+    flags: HashMap<git2::Oid, u8>,
+    /// The sides each visited commit was reachable from when last visited,
+    /// and its position in the walk.
+    visited: HashMap<git2::Oid, (u8, Key)>,
+    /// The visited commits, in the order of their visits.
+    order: Vec<git2::Oid>,
+    queue: BinaryHeap<Entry>,
+    /// The number of visited commits reachable from `LOCAL` only.
+    ahead: usize,
+    /// The number of visited commits reachable from `OTHER` only.
+    behind: usize,
+    /// Whether a commit was found to be newer than one of its children.
+    skewed: bool,
+}
+
+impl<'a> Walk<'a> {
+    fn new(walker: &'a Walker, repo: &'a git2::Repository) -> Self {
+        Walk {
+            walker,
+            repo,
+            flags: HashMap::new(),
+            visited: HashMap::new(),
+            order: Vec::new(),
+            queue: BinaryHeap::new(),
+            ahead: 0,
+            behind: 0,
+            skewed: false,
+        }
+    }
+
+    /// Marks `oid`, a parent of a commit at `child`, if given, as reachable
+    /// from `side`, queueing it for a visit if that is news.
+    fn mark(&mut self, oid: git2::Oid, side: u8, child: Option<Key>) -> anyhow::Result<()> {
+        let flags = self.flags.entry(oid).or_default();
+        if *flags | side != *flags {
+            *flags |= side;
+            let entry = self.walker.entry(self.repo, oid)?;
+            if child.is_some_and(|child| entry.key() > child) {
+                self.skewed = true;
+            }
+            self.queue.push(entry);
+        }
+        Ok(())
+    }
+
+    /// Passes on the sides `entry` is reachable from to its parents, unless
+    /// that was already done. Returns whether it was done now.
+    fn visit(&mut self, entry: Entry) -> anyhow::Result<bool> {
+        let side = self.flags[&entry.oid];
+        let previous = self.visited.insert(entry.oid, (side, entry.key()));
+        if let Some((previous, _)) = previous {
+            if previous == side {
+                return Ok(false);
+            }
+            // Commits with the same commit time may be visited before
+            // their descendants.
+            self.count(previous, -1);
+        }
+        self.count(side, 1);
+        self.order.push(entry.oid);
+        for parent in self.repo.find_commit(entry.oid)?.parent_ids() {
+            self.mark(parent, side, Some(entry.key()))?;
+        }
+        Ok(true)
+    }
+
+    fn count(&mut self, side: u8, delta: isize) {
+        let counter = match side {
+            LOCAL => &mut self.ahead,
+            OTHER => &mut self.behind,
+            _ => return,
+        };
+        *counter = counter.wrapping_add_signed(delta);
+    }
+
+    /// Returns the counts that are final, or known to exceed `limit`.
+    ///
+    /// Must not be called after running into clock skew.
+    fn known_counts(&self, limit: usize) -> (Option<usize>, Option<usize>) {
+        let (mut local_pending, mut other_pending) = (false, false);
+        for entry in &self.queue {
+            let side = self.flags[&entry.oid];
+            if self.visited.get(&entry.oid).map(|(visited, _)| *visited) == Some(side) {
+                continue;
+            }
+            match side {
+                LOCAL => local_pending = true,
+                OTHER => other_pending = true,
+                _ => {}
+            }
+        }
+        // Visited commits may still turn out to be reachable from the other
+        // side, too, if commits with the same position are left to visit.
+        let (mut local_uncertain, mut other_uncertain) = (0, 0);
+        if let Some(next) = self.queue.peek() {
+            for oid in self.order.iter().rev() {
+                if self.visited[oid].1 > next.key() {
+                    break;
+                }
+                match self.flags[oid] {
+                    LOCAL => local_uncertain += 1,
+                    OTHER => other_uncertain += 1,
+                    _ => {}
+                }
+            }
+        }
+        let known = |count: usize, pending: bool, uncertain: usize| {
+            let known = (!pending && uncertain == 0) || count.saturating_sub(uncertain) > limit;
+            Some(count).filter(|_| known)
+        };
+        (
+            known(self.ahead, local_pending, local_uncertain),
+            known(self.behind, other_pending, other_uncertain),
+        )
+    }
+}
+
+impl Walker {
+    /// Creates a walker that counts commits up to `limit`, if given.
+    pub fn new(repo: &git2::Repository, limit: Option<usize>) -> anyhow::Result<Self> {
+        let graph = match limit {
+            Some(_) => CommitGraph::open(repo)?,
+            None => None,
+        };
+        Ok(Walker { limit, graph })
+    }
+
+    /// Counts the commits reachable from only `local` or only `other`.
+    pub fn distance(
+        &self,
+        repo: &git2::Repository,
+        local: git2::Oid,
+        other: git2::Oid,
+    ) -> anyhow::Result<Distance> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => {
+                let (ahead, behind) = repo.graph_ahead_behind(local, other)?;
+                return Ok(Distance {
+                    ahead: Some(ahead),
+                    behind: Some(behind),
+                    limit: None,
+                });
+            }
+        };
+        let (ahead, behind) = match self.walk(repo, local, other, limit)? {
+            Some(counts) => counts,
+            None => {
+                let (ahead, behind) = repo.graph_ahead_behind(local, other)?;
+                (Some(ahead), Some(behind))
+            }
+        };
+        let cut_short = |count: Option<usize>| count.is_none_or(|count| count > limit);
+        let capped = limit.saturating_add(1);
+        Ok(Distance {
+            ahead: ahead.map(|ahead| ahead.min(capped)),
+            behind: behind.map(|behind| behind.min(capped)),
+            limit: Some(limit).filter(|_| cut_short(ahead) || cut_short(behind)),
+        })
+    }
+
+    /// Walks the history until the counts are known, or known to exceed
+    /// `limit`, or it runs out of steps, leaving the remaining counts
+    /// unknown. Returns `None` if it runs into clock skew.
+    fn walk(
+        &self,
+        repo: &git2::Repository,
+        local: git2::Oid,
+        other: git2::Oid,
+        limit: usize,
+    ) -> anyhow::Result<Option<(Option<usize>, Option<usize>)>> {
+        let mut walk = Walk::new(self, repo);
+        walk.mark(local, LOCAL, None)?;
+        walk.mark(other, OTHER, None)?;
+        let max_steps = STEPS_PER_LIMIT.saturating_mul(limit.saturating_add(1));
+        let mut steps = 0;
+        while let Some(entry) = walk.queue.pop() {
+            if !walk.visit(entry)? {
+                continue;
+            }
+            steps += 1;
+            if walk.skewed {
+                return Ok(None);
+            }
+            let counts = walk.known_counts(limit);
+            if (counts.0.is_some() && counts.1.is_some()) || steps >= max_steps {
+                return Ok(Some(counts));
+            }
+        }
+        Ok(Some((Some(walk.ahead), Some(walk.behind))))
+    }
+
+    fn entry(&self, repo: &git2::Repository, oid: git2::Oid) -> anyhow::Result<Entry> {
+        let generation = match &self.graph {
+            Some(graph) => graph.generation(oid)?,
+            None => None,
+        };
+        Ok(Entry {
+            generation: generation.unwrap_or(u32::MAX),
+            time: repo.find_commit(oid)?.time().seconds(),
+            oid,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process::Command;
+
+    use tempfile::TempDir;
+
+    /// A repository with commits made at chosen times.
+    struct History {
+        _dir: TempDir,
+        repo: git2::Repository,
+    }
+
+    impl History {
+        fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            let repo = git2::Repository::init(dir.path()).unwrap();
+            History { _dir: dir, repo }
+        }
+
+        fn commit(&self, parents: &[git2::Oid], time: i64) -> git2::Oid {
+            let signature =
+                git2::Signature::new("Test", "test@example.com", &git2::Time::new(time, 0))
+                    .unwrap();
+            let tree = self.repo.treebuilder(None).unwrap().write().unwrap();
+            let tree = self.repo.find_tree(tree).unwrap();
+            let parents: Vec<_> = parents
+                .iter()
+                .map(|&parent| self.repo.find_commit(parent).unwrap())
+                .collect();
+            let parents: Vec<_> = parents.iter().collect();
+            self.repo
+                .commit(None, &signature, &signature, "", &tree, &parents)
+                .unwrap()
+        }
+
+        /// Adds `n` commits on top of `parent`, at times given by `time`.
+        fn chain(&self, mut parent: git2::Oid, n: usize, time: impl Fn(usize) -> i64) -> git2::Oid {
+            for i in 0..n {
+                parent = self.commit(&[parent], time(i));
+            }
+            parent
+        }
+
+        fn write_commit_graph(&self) {
+            let status = Command::new("git")
+                .args(["commit-graph", "write", "--reachable"])
+                .env("GIT_DIR", self.repo.path())
+                .status()
+                .unwrap();
+            assert!(status.success());
+            // A commit-graph only covers commits reachable from refs.
+            assert!(CommitGraph::open(&self.repo).unwrap().is_some());
+        }
+
+        /// Checks the distance between `local` and `other` against libgit2's
+        /// count, at limits around the counts on either side, first without
+        /// and then with a commit-graph. Counts may be left unknown, but
+        /// only when counting was cut short.
+        fn check(&self, local: git2::Oid, other: git2::Oid) {
+            self.repo
+                .reference("refs/heads/local", local, true, "")
+                .unwrap();
+            self.repo
+                .reference("refs/heads/other", other, true, "")
+                .unwrap();
+            let (ahead, behind) = self.repo.graph_ahead_behind(local, other).unwrap();
+            let mut limits = vec![0, 1, usize::MAX];
+            for count in [ahead, behind] {
+                limits.extend([count.saturating_sub(1), count, count + 1]);
+            }
+            for graph in [false, true] {
+                if graph {
+                    self.write_commit_graph();
+                }
+                for &limit in &limits {
+                    let walker = Walker::new(&self.repo, Some(limit)).unwrap();
+                    let distance = walker.distance(&self.repo, local, other).unwrap();
+                    let capped = limit.saturating_add(1);
+                    let known =
+                        |count: usize, known: Option<usize>| known.map(|_| count.min(capped));
+                    let (known_ahead, known_behind) =
+                        (known(ahead, distance.ahead), known(behind, distance.behind));
+                    let cut_short = ahead > limit
+                        || behind > limit
+                        || known_ahead.is_none()
+                        || known_behind.is_none();
+                    let expected = Distance {
+                        ahead: known_ahead,
+                        behind: known_behind,
+                        limit: Some(limit).filter(|_| cut_short),
+                    };
+                    assert_eq!(distance, expected, "limit {}, graph {}", limit, graph);
+                    if limit == usize::MAX {
+                        assert_eq!(distance.limit, None, "graph {}", graph);
+                    }
+                }
+            }
+        }
+    }
+
+    fn minutes(start: i64) -> impl Fn(usize) -> i64 {
+        move |i| start + 60 * i as i64
+    }
+
+    #[test]
+    fn diverged() {
+        for &(ahead, behind) in &[(0, 0), (0, 5), (3, 60), (25, 7), (12, 12)] {
+            let history = History::new();
+            let base = history.chain(history.commit(&[], 0), 20, minutes(60));
+            let local = history.chain(base, ahead, minutes(10_000));
+            let other = history.chain(base, behind, minutes(20_000));
+            history.check(local, other);
+        }
+    }
+
+    #[test]
+    fn merged() {
+        let history = History::new();
+        let base = history.chain(history.commit(&[], 0), 10, minutes(60));
+        let (mut local, mut other) = (base, base);
+        for i in 0..5 {
+            let time = 10_000 * (i + 1);
+            local = history.chain(local, 4, minutes(time));
+            other = history.chain(other, 2, minutes(time + 1000));
+            local = history.commit(&[local, other], time + 2000);
+        }
+        other = history.chain(other, 3, minutes(100_000));
+        history.check(local, other);
+        history.check(other, local);
+    }
+
+    #[test]
+    fn same_time() {
+        let history = History::new();
+        let base = history.chain(history.commit(&[], 0), 30, |_| 0);
+        let mut local = history.chain(base, 3, |_| 0);
+        let other = history.chain(base, 8, |_| 0);
+        local = history.commit(&[local, history.chain(base, 2, |_| 0)], 0);
+        history.check(local, other);
+    }
+
+    #[test]
+    fn skewed() {
+        // Commit times going backwards, and back and forth.
+        for &step in &[-60, 60] {
+            let history = History::new();
+            let base = history.chain(history.commit(&[], 0), 20, |i| step * i as i64);
+            let jitter = |i: usize| {
+                if i.is_multiple_of(3) {
+                    100_000
+                } else {
+                    10 * i as i64
+                }
+            };
+            let local = history.chain(base, 9, jitter);
+            let other = history.chain(base, 4, |i| -step * i as i64);
+            history.check(local, other);
+        }
+    }
+
+    #[test]
+    fn bounded() {
+        let history = History::new();
+        let base = history.chain(history.commit(&[], 0), 10, minutes(60));
+        let local = history.chain(base, 500, minutes(10_000));
+        let other = history.chain(base, 500, minutes(10_030));
+        let walker = Walker::new(&history.repo, Some(10)).unwrap();
+        // Both sides exceed the limit long before the merge base is reached,
+        // so there is no need to fall back to counting all commits.
+        let counts = walker.walk(&history.repo, local, other, 10).unwrap();
+        assert!(matches!(
+            counts,
+            Some((Some(ahead), Some(behind))) if ahead > 10 && behind > 10
+        ));
+        history.check(local, other);
+    }
+
+    #[test]
+    fn bounded_on_one_side() {
+        let history = History::new();
+        let base = history.chain(history.commit(&[], 0), 10, minutes(60));
+        let local = history.chain(base, 3, minutes(20_000));
+        let other = history.chain(base, 500, minutes(10_000));
+        let walker = Walker::new(&history.repo, Some(10)).unwrap();
+        // Whether the local commits are reachable from the other side is
+        // only known once the merge base is reached, which takes too long.
+        let distance = walker.distance(&history.repo, local, other).unwrap();
+        let expected = Distance {
+            ahead: None,
+            behind: Some(11),
+            limit: Some(10),
+        };
+        assert_eq!(distance, expected);
+        let walker = Walker::new(&history.repo, Some(600)).unwrap();
+        let distance = walker.distance(&history.repo, local, other).unwrap();
+        let expected = Distance {
+            ahead: Some(3),
+            behind: Some(500),
+            limit: None,
+        };
+        assert_eq!(distance, expected);
+        history.check(local, other);
+    }
+}
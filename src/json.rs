@@ -0,0 +1,395 @@
+//! Structured output as a JSON document.
+//!
+//! The document layout is decoupled from the internal types, so it can stay
+//! stable while those evolve. Incompatible changes to the layout must bump
+//! `VERSION`; adding fields is considered compatible.
+
+use serde::Serialize;
+
+use crate::{dirty, state, status, BranchInfo, GitInfo, Remote};
+
+/// Version of the JSON document layout.
+pub const VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct Document<'a> {
+    version: u32,
+    head: Head<'a>,
+    upstream: Option<Upstream<'a>>,
+    push: Option<Target<'a>>,
+    status: Option<Status>,
+    dirty: Option<Dirty>,
+    state: Option<State<'a>>,
+    stashes: usize,
+    default_branch: Option<Target<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Head<'a> {
+    Branch {
+        name: &'a str,
+        oid: String,
+        short_oid: &'a str,
+    },
+    Detached {
+        oid: String,
+        short_oid: &'a str,
+        description: Option<&'a str>,
+    },
+    Unborn,
+}
+
+#[derive(Serialize)]
+struct Upstream<'a> {
+    branch: &'a str,
+    remote: Option<&'a str>,
+    /// The name of the branch on the remote.
+    name: &'a str,
+    /// Whether `name` differs from the local branch name.
+    mismatch: bool,
+    distance: Option<Distance>,
+    /// Whether the upstream branch no longer exists.
+    gone: bool,
+    /// The configured merge reference of a gone upstream.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    merge: Option<&'a str>,
+}
+
+/// A branch compared against `HEAD`.
+#[derive(Serialize)]
+struct Target<'a> {
+    branch: &'a str,
+    distance: Option<Distance>,
+}
+
+#[derive(Serialize)]
+struct Distance {
+    ahead: Option<usize>,
+    behind: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Status {
+    staged: u32,
+    conflicts: u32,
+    unmerged: Conflicts,
+    changed: u32,
+    untracked: Option<u32>,
+    index: Changes,
+    worktree: Changes,
+}
+
+#[derive(Serialize)]
+struct Conflicts {
+    both_modified: u32,
+    both_added: u32,
+    both_deleted: u32,
+    added_by_us: u32,
+    added_by_them: u32,
+    deleted_by_us: u32,
+    deleted_by_them: u32,
+}
+
+#[derive(Serialize)]
+struct Changes {
+    added: u32,
+    modified: u32,
+    deleted: u32,
+    renamed: u32,
+    typechanged: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    insertions: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deletions: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Dirty {
+    index: bool,
+    worktree: bool,
+    untracked: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct State<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    progress: Option<Progress>,
+    branch: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct Progress {
+    step: usize,
+    total: usize,
+}
+
+impl From<crate::Distance> for Distance {
+    fn from(distance: crate::Distance) -> Self {
+        Distance {
+            ahead: distance.ahead,
+            behind: distance.behind,
+            limit: distance.limit,
+        }
+    }
+}
+
+impl From<&status::Status> for Status {
+    fn from(status: &status::Status) -> Self {
+        let unmerged = &status.unmerged;
+        Status {
+            staged: status.staged,
+            conflicts: status.conflicts,
+            unmerged: Conflicts {
+                both_modified: unmerged.both_modified,
+                both_added: unmerged.both_added,
+                both_deleted: unmerged.both_deleted,
+                added_by_us: unmerged.added_by_us,
+                added_by_them: unmerged.added_by_them,
+                deleted_by_us: unmerged.deleted_by_us,
+                deleted_by_them: unmerged.deleted_by_them,
+            },
+            changed: status.changed,
+            untracked: status.untracked,
+            index: Changes::from(&status.index),
+            worktree: Changes::from(&status.worktree),
+        }
+    }
+}
+
+impl From<&status::Changes> for Changes {
+    fn from(changes: &status::Changes) -> Self {
+        Changes {
+            added: changes.added,
+            modified: changes.modified,
+            deleted: changes.deleted,
+            renamed: changes.renamed,
+            typechanged: changes.typechanged,
+            insertions: changes.insertions,
+            deletions: changes.deletions,
+        }
+    }
+}
+
+impl From<&dirty::Dirty> for Dirty {
+    fn from(dirty: &dirty::Dirty) -> Self {
+        Dirty {
+            index: dirty.index,
+            worktree: dirty.worktree,
+            untracked: dirty.untracked,
+        }
+    }
+}
+
+impl<'a> From<&'a state::State> for State<'a> {
+    fn from(state: &'a state::State) -> Self {
+        State {
+            kind: state.name(),
+            progress: state.progress.map(|p| Progress {
+                step: p.step,
+                total: p.total,
+            }),
+            branch: state.branch.as_deref(),
+        }
+    }
+}
+
+impl<'a> From<&'a Remote> for Target<'a> {
+    fn from(remote: &'a Remote) -> Self {
+        Target {
+            branch: &remote.branch,
+            distance: remote.distance().map(Distance::from),
+        }
+    }
+}
+
+impl<'a> Upstream<'a> {
+    fn new(branch: &'a BranchInfo, upstream: &'a crate::Upstream) -> Self {
+        let (distance, merge) = match upstream {
+            crate::Upstream::Tracking(remote) => (remote.distance().map(Distance::from), None),
+            crate::Upstream::Gone { merge, .. } => (None, Some(merge.as_str())),
+        };
+        Upstream {
+            branch: upstream.branch(),
+            remote: upstream.remote(),
+            name: upstream.name(),
+            mismatch: branch.upstream_mismatch(),
+            distance,
+            gone: merge.is_some(),
+            merge,
+        }
+    }
+}
+
+/// Serializes `info` into a single-line JSON document.
+pub fn to_string(info: &GitInfo) -> serde_json::Result<String> {
+    let head = match &info.head {
+        crate::Head::Branch {
+            branch,
+            oid,
+            short_id,
+        } => Head::Branch {
+            name: &branch.name,
+            oid: oid.to_string(),
+            short_oid: short_id,
+        },
+        crate::Head::Detached {
+            oid,
+            short_id,
+            description,
+        } => Head::Detached {
+            oid: oid.to_string(),
+            short_oid: short_id,
+            description: description.as_deref(),
+        },
+        crate::Head::Unborn => Head::Unborn,
+    };
+    let branch = info.branch();
+    let document = Document {
+        version: VERSION,
+        head,
+        upstream: branch.and_then(|branch| {
+            let upstream = branch.upstream.as_ref()?;
+            Some(Upstream::new(branch, upstream))
+        }),
+        push: branch
+            .and_then(|branch| branch.push.as_ref())
+            .map(Target::from),
+        status: info.status.as_ref().map(Status::from),
+        dirty: info.dirty.as_ref().map(Dirty::from),
+        state: info.state.as_ref().map(State::from),
+        stashes: info.stashes,
+        default_branch: info.default_branch.as_ref().map(Target::from),
+    };
+    serde_json::to_string(&document)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::{Changes, Conflicts};
+
+    fn remote(branch: &str, ahead: usize, behind: usize) -> Remote {
+        Remote {
+            branch: branch.into(),
+            remote: Some("origin".into()),
+            distance: Some(crate::Distance {
+                ahead: Some(ahead),
+                behind: Some(behind),
+                limit: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let oid = git2::Oid::from_str("5e62dae0123456789abcdef0123456789abcdef0").unwrap();
+        let info = GitInfo {
+            head: crate::Head::Branch {
+                branch: BranchInfo {
+                    name: "topic".into(),
+                    upstream: Some(crate::Upstream::Tracking(remote("origin/main", 1, 0))),
+                    push: Some(remote("origin/topic", 2, 0)),
+                },
+                oid,
+                short_id: "5e62dae".into(),
+            },
+            status: Some(crate::Status {
+                staged: 1,
+                conflicts: 1,
+                unmerged: Conflicts {
+                    both_modified: 1,
+                    ..Conflicts::default()
+                },
+                changed: 2,
+                untracked: Some(1),
+                index: Changes {
+                    modified: 1,
+                    insertions: Some(3),
+                    deletions: Some(4),
+                    ..Changes::default()
+                },
+                worktree: Changes {
+                    added: 1,
+                    deleted: 1,
+                    ..Changes::default()
+                },
+            }),
+            dirty: Some(crate::Dirty {
+                index: true,
+                worktree: true,
+                untracked: Some(true),
+            }),
+            state: Some(crate::State {
+                kind: git2::RepositoryState::RebaseInteractive,
+                progress: Some(crate::Progress { step: 2, total: 5 }),
+                branch: Some("topic".into()),
+            }),
+            stashes: 3,
+            default_branch: Some(Remote {
+                distance: Some(crate::Distance {
+                    ahead: None,
+                    behind: Some(101),
+                    limit: Some(100),
+                }),
+                ..remote("origin/main", 0, 0)
+            }),
+        };
+        let expected = json!({
+            "version": 1,
+            "head": {
+                "type": "branch",
+                "name": "topic",
+                "oid": "5e62dae0123456789abcdef0123456789abcdef0",
+                "short_oid": "5e62dae",
+            },
+            "upstream": {
+                "branch": "origin/main",
+                "remote": "origin",
+                "name": "main",
+                "mismatch": true,
+                "distance": { "ahead": 1, "behind": 0 },
+                "gone": false,
+            },
+            "push": { "branch": "origin/topic", "distance": { "ahead": 2, "behind": 0 } },
+            "status": {
+                "staged": 1,
+                "conflicts": 1,
+                "unmerged": {
+                    "both_modified": 1, "both_added": 0, "both_deleted": 0,
+                    "added_by_us": 0, "added_by_them": 0,
+                    "deleted_by_us": 0, "deleted_by_them": 0,
+                },
+                "changed": 2,
+                "untracked": 1,
+                "index": {
+                    "added": 0, "modified": 1, "deleted": 0, "renamed": 0,
+                    "typechanged": 0, "insertions": 3, "deletions": 4,
+                },
+                "worktree": {
+                    "added": 1, "modified": 0, "deleted": 1, "renamed": 0,
+                    "typechanged": 0,
+                },
+            },
+            "dirty": { "index": true, "worktree": true, "untracked": true },
+            "state": {
+                "type": "rebase-interactive",
+                "progress": { "step": 2, "total": 5 },
+                "branch": "topic",
+            },
+            "stashes": 3,
+            "default_branch": {
+                "branch": "origin/main",
+                "distance": { "ahead": null, "behind": 101, "limit": 100 },
+            },
+        });
+        let document: serde_json::Value = serde_json::from_str(&to_string(&info).unwrap()).unwrap();
+        assert_eq!(document, expected);
+    }
+}
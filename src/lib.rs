@@ -0,0 +1,869 @@
+//! Git status for shell prompt integration.
+//!
+//! This crate provides the information shown by the `gitstat` binary: the
+//! current branch, its distance to the upstream branch, counts of changed
+//! files and the like. Use `query` to obtain a `GitInfo` for a directory:
+//!
+//! ```
+//! let info = gitstat::query(".", &gitstat::Options::default())?;
+//! if let Some(info) = info {
+//!     println!("{:?}", info.head);
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! If you already have an open repository, use `GitInfo::from_repo` instead.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::iter;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+mod budget;
+pub mod cache;
+mod commit_graph;
+pub mod daemon;
+mod describe;
+mod dirty;
+mod distance;
+pub mod json;
+mod push;
+pub mod state;
+mod status;
+pub mod template;
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod test_repo;
+#[cfg(target_os = "linux")]
+mod watch;
+
+pub use dirty::Dirty;
+use distance::Walker;
+pub use state::{Progress, State};
+pub use status::{Changes, Conflicts, Status};
+use template::Template;
+
+/// A local branch checked out as `HEAD`.
+///
+/// Branch names are not necessarily valid UTF-8; invalid bytes in names are
+/// represented as `\xNN` escapes, which cannot be confused with a valid
+/// name, as these never contain a backslash.
+#[derive(Debug, Clone)]
+pub struct BranchInfo {
+    /// The short name of the branch, e.g. `main`.
+    pub name: String,
+    /// The upstream branch, if one is configured.
+    pub upstream: Option<Upstream>,
+    /// The remote-tracking branch of the push destination, i.e. `@{push}`,
+    /// if the branch would be pushed and has been pushed before.
+    pub push: Option<Remote>,
+}
+
+impl BranchInfo {
+    /// Returns the upstream branch, if it exists.
+    pub fn remote(&self) -> Option<&Remote> {
+        match &self.upstream {
+            Some(Upstream::Tracking(remote)) => Some(remote),
+            _ => None,
+        }
+    }
+
+    /// Checks whether the upstream branch has a different name than the
+    /// local branch, in which case `git push` may not do what is expected.
+    pub fn upstream_mismatch(&self) -> bool {
+        self.upstream
+            .as_ref()
+            .is_some_and(|upstream| upstream.name() != self.name)
+    }
+}
+
+/// The configured upstream of a local branch.
+#[derive(Debug, Clone)]
+pub enum Upstream {
+    /// The upstream branch exists.
+    Tracking(Remote),
+    /// The upstream branch does not exist (anymore), typically because it
+    /// was deleted on the remote after being merged.
+    Gone {
+        /// The short name of the missing upstream branch, e.g.
+        /// `origin/topic`.
+        branch: String,
+        /// The configured remote, `None` for a local upstream branch.
+        remote: Option<String>,
+        /// The configured `branch.<name>.merge` reference, e.g.
+        /// `refs/heads/topic`.
+        merge: String,
+    },
+}
+
+impl Upstream {
+    /// Returns the short name of the upstream branch, e.g. `origin/main`.
+    pub fn branch(&self) -> &str {
+        match self {
+            Upstream::Tracking(remote) => &remote.branch,
+            Upstream::Gone { branch, .. } => branch,
+        }
+    }
+
+    /// Returns the name of the upstream's remote, `None` for a local
+    /// upstream branch.
+    pub fn remote(&self) -> Option<&str> {
+        match self {
+            Upstream::Tracking(remote) => remote.remote.as_deref(),
+            Upstream::Gone { remote, .. } => remote.as_deref(),
+        }
+    }
+
+    /// Returns the name of the upstream branch on its remote, e.g. `main`.
+    pub fn name(&self) -> &str {
+        match self {
+            Upstream::Tracking(remote) => remote.name(),
+            Upstream::Gone { merge, .. } => merge.strip_prefix("refs/heads/").unwrap_or(merge),
+        }
+    }
+
+    fn from_repo(
+        repo: &git2::Repository,
+        branch: &git2::Branch,
+        walker: Option<&Walker>,
+    ) -> anyhow::Result<Option<Self>> {
+        match branch.upstream() {
+            Ok(upstream) => {
+                let local = branch.get().peel_to_commit()?.id();
+                let remote = Remote::from_repo(repo, local, upstream.get(), walker)?;
+                Ok(Some(Upstream::Tracking(remote)))
+            }
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Self::gone(repo, branch),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Looks for an upstream configured for `branch`, which must be missing,
+    /// as `branch.upstream()` did not find it.
+    fn gone(repo: &git2::Repository, branch: &git2::Branch) -> anyhow::Result<Option<Self>> {
+        // Configuration keys are strings, so there's no way to look up the
+        // upstream of a branch whose name isn't valid UTF-8.
+        let refname = match branch.get().name() {
+            Some(refname) => refname,
+            None => return Ok(None),
+        };
+        let name = refname.strip_prefix("refs/heads/").unwrap_or(refname);
+        let config = repo.config()?.snapshot()?;
+        let merge = match config.get_bytes(&format!("branch.{}.merge", name)) {
+            Ok(merge) => merge.to_vec(),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // Determining the name of the remote-tracking branch fails if the
+        // remote has been removed as well.
+        let upstream = repo
+            .branch_upstream_name(refname)
+            .map(|buf| buf.to_vec())
+            .unwrap_or_else(|_| merge.clone());
+        let remote = repo
+            .branch_upstream_remote(refname)
+            .ok()
+            .and_then(|remote| remote.as_str().map(String::from))
+            .filter(|remote| remote != ".");
+        Ok(Some(Upstream::Gone {
+            branch: escape_name(shorten_branch(&upstream)),
+            remote,
+            merge: escape_name(&merge),
+        }))
+    }
+}
+
+/// A remote-tracking branch a local branch is compared against, such as its
+/// upstream branch.
+#[derive(Debug, Clone)]
+pub struct Remote {
+    /// The short name of the branch, e.g. `origin/main`.
+    pub branch: String,
+    /// The remote the branch belongs to, `None` for a local branch.
+    pub remote: Option<String>,
+    /// How far the local branch has diverged from this branch, if
+    /// determined.
+    pub distance: Option<Distance>,
+}
+
+impl Remote {
+    /// Looks up the push destination of `branch`.
+    fn push(
+        repo: &git2::Repository,
+        branch: &git2::Branch,
+        walker: &Walker,
+    ) -> anyhow::Result<Option<Self>> {
+        let tracking = match branch.get().name() {
+            Some(refname) => push::tracking_ref(repo, refname)?,
+            None => None,
+        };
+        let tracking = match tracking {
+            Some(tracking) => tracking,
+            None => return Ok(None),
+        };
+        match repo.find_reference(&tracking) {
+            Ok(target) => {
+                let local = branch.get().peel_to_commit()?.id();
+                Ok(Some(Self::from_repo(repo, local, &target, Some(walker))?))
+            }
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Looks up the default branch to compare the commit `local` against.
+    ///
+    /// This is the branch `refs/remotes/<remote>/HEAD` refers to, where
+    /// `<remote>` is the remote of the current branch's upstream, or
+    /// `origin`. If that is not known, the first of `names` that exists as
+    /// a remote-tracking branch of that remote, or else as a local branch,
+    /// is used.
+    fn default_branch(
+        repo: &git2::Repository,
+        local: git2::Oid,
+        names: &[String],
+        walker: &Walker,
+    ) -> anyhow::Result<Option<Self>> {
+        for candidate in default_branch_candidates(repo, names)? {
+            // A dangling `HEAD` fails to resolve, just like a missing branch.
+            match repo
+                .find_reference(&candidate)
+                .and_then(|reference| reference.resolve())
+            {
+                Ok(target) => {
+                    return Ok(Some(Self::from_repo(repo, local, &target, Some(walker))?))
+                }
+                Err(e)
+                    if e.code() == git2::ErrorCode::NotFound
+                        || e.code() == git2::ErrorCode::InvalidSpec =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Compares the commit `local` against the branch `target`, if a
+    /// `walker` is given.
+    fn from_repo(
+        repo: &git2::Repository,
+        local: git2::Oid,
+        target: &git2::Reference,
+        walker: Option<&Walker>,
+    ) -> anyhow::Result<Self> {
+        let distance = match walker {
+            Some(walker) => Some(walker.distance(repo, local, target.peel_to_commit()?.id())?),
+            None => None,
+        };
+        let remote = target
+            .name()
+            .filter(|refname| refname.starts_with("refs/remotes/"))
+            .and_then(|refname| repo.branch_remote_name(refname).ok())
+            .and_then(|remote| remote.as_str().map(String::from));
+        Ok(Remote {
+            branch: escape_name(target.shorthand_bytes()),
+            remote,
+            distance,
+        })
+    }
+}
+
+/// What `HEAD` refers to.
+#[derive(Debug)]
+pub enum Head {
+    /// A local branch, pointing to the commit `oid`.
+    Branch {
+        /// The branch, along with the branches it is compared against.
+        branch: BranchInfo,
+        /// The commit the branch points to.
+        oid: git2::Oid,
+        /// The unique abbreviation of `oid`.
+        short_id: String,
+    },
+    /// The commit `oid`, without being on a branch.
+    Detached {
+        /// The commit checked out.
+        oid: git2::Oid,
+        /// The unique abbreviation of `oid`.
+        short_id: String,
+        /// A human-friendly name for the commit, such as a tag or a
+        /// `git describe` style `v1.2-3-gabc1234`, if one was found, and
+        /// looked for as requested by `Parts::description`.
+        description: Option<String>,
+    },
+    /// A branch without any commits yet.
+    Unborn,
+}
+
+impl Head {
+    fn from_repo(
+        repo: &git2::Repository,
+        options: &Options,
+        walker: Option<&Walker>,
+    ) -> anyhow::Result<Self> {
+        let head = match repo.head() {
+            Ok(head) => head,
+            Err(e) if e.code() == git2::ErrorCode::UnbornBranch => return Ok(Head::Unborn),
+            Err(e) => return Err(e.into()),
+        };
+        let commit = head.peel_to_commit()?;
+        let short_id = abbreviate(repo, commit.id(), options.abbrev)?;
+        if repo.head_detached()? {
+            return Ok(Head::Detached {
+                oid: commit.id(),
+                description: if options.parts.description {
+                    describe::describe(repo, commit.id(), short_id.len())?
+                } else {
+                    None
+                },
+                short_id,
+            });
+        }
+        let name = escape_name(head.shorthand_bytes());
+        let branch = git2::Branch::wrap(head);
+        let push = match walker {
+            Some(walker) if options.parts.push => Remote::push(repo, &branch, walker)?,
+            _ => None,
+        };
+        Ok(Head::Branch {
+            branch: BranchInfo {
+                name,
+                upstream: Upstream::from_repo(
+                    repo,
+                    &branch,
+                    walker.filter(|_| options.parts.distance),
+                )?,
+                push,
+            },
+            oid: commit.id(),
+            short_id,
+        })
+    }
+
+    /// Returns the unique abbreviation of the commit `HEAD` points to, if
+    /// any.
+    pub fn short_id(&self) -> Option<&str> {
+        match self {
+            Head::Branch { short_id, .. } | Head::Detached { short_id, .. } => Some(short_id),
+            Head::Unborn => None,
+        }
+    }
+
+    /// Returns the commit `HEAD` points to, if any.
+    pub fn oid(&self) -> Option<git2::Oid> {
+        match self {
+            Head::Branch { oid, .. } | Head::Detached { oid, .. } => Some(*oid),
+            Head::Unborn => None,
+        }
+    }
+}
+
+/// Everything `gitstat` reports about a repository.
+#[derive(Debug)]
+pub struct GitInfo {
+    /// What `HEAD` refers to.
+    pub head: Head,
+    /// The working tree status, `None` if not determined.
+    pub status: Option<Status>,
+    /// Whether there are changes, derived from `status`, or determined on
+    /// its own with `Options::dirty_only`.
+    pub dirty: Option<Dirty>,
+    /// The operation in progress, if any.
+    pub state: Option<State>,
+    /// Number of stash entries.
+    pub stashes: usize,
+    /// The default branch of the remote, e.g. `origin/main`, compared
+    /// against `HEAD`.
+    pub default_branch: Option<Remote>,
+}
+
+impl GitInfo {
+    /// Gathers all information about `repo`.
+    ///
+    /// Only the options that don't concern locating the repository are
+    /// taken into account.
+    pub fn from_repo(repo: &git2::Repository, options: &Options) -> anyhow::Result<Self> {
+        if options.counts_files() {
+            return Self::with_status(repo, Status::from_repo(repo)?, options);
+        }
+        let dirty = if options.parts.dirty {
+            Some(Dirty::from_repo(repo)?)
+        } else {
+            None
+        };
+        Ok(GitInfo {
+            dirty,
+            ..Self::without_status(repo, options)?
+        })
+    }
+
+    /// Like `from_repo`, but using an already computed `status`.
+    pub fn with_status(
+        repo: &git2::Repository,
+        status: Status,
+        options: &Options,
+    ) -> anyhow::Result<Self> {
+        let status = if options.counts_lines() {
+            status.count_lines(repo)?
+        } else {
+            status
+        };
+        Ok(GitInfo {
+            dirty: Some(Dirty::from_status(&status)),
+            status: Some(status),
+            ..Self::without_status(repo, options)?
+        })
+    }
+
+    /// Gathers everything but the working tree status.
+    pub fn without_status(repo: &git2::Repository, options: &Options) -> anyhow::Result<Self> {
+        let parts = &options.parts;
+        // The walker opens the commit-graph, which is only needed for
+        // comparing branches.
+        let walker = if parts.distance || parts.push || parts.default_branch {
+            Some(Walker::new(repo, options.max_distance)?)
+        } else {
+            None
+        };
+        let head = Head::from_repo(repo, options, walker.as_ref())?;
+        let default_branch = match (head.oid(), &walker) {
+            (Some(oid), Some(walker)) if parts.default_branch => {
+                Remote::default_branch(repo, oid, &options.default_branches, walker)?
+            }
+            _ => None,
+        };
+        Ok(GitInfo {
+            head,
+            status: None,
+            dirty: None,
+            state: if parts.state {
+                State::from_repo(repo)?
+            } else {
+                None
+            },
+            stashes: if parts.stashes {
+                count_stashes(repo)?
+            } else {
+                0
+            },
+            default_branch,
+        })
+    }
+
+    /// Returns the current branch, if any.
+    pub fn branch(&self) -> Option<&BranchInfo> {
+        match &self.head {
+            Head::Branch { branch, .. } => Some(branch),
+            _ => None,
+        }
+    }
+
+    /// Returns the upstream configuration of the current branch, if any.
+    pub fn upstream(&self) -> Option<&Upstream> {
+        self.branch().and_then(|branch| branch.upstream.as_ref())
+    }
+
+    /// Returns the distance to the upstream branch, if known.
+    pub fn distance(&self) -> Option<Distance> {
+        self.branch()
+            .and_then(BranchInfo::remote)
+            .and_then(Remote::distance)
+    }
+
+    /// Returns the distance to the push destination, if known.
+    pub fn push_distance(&self) -> Option<Distance> {
+        self.branch()
+            .and_then(|branch| branch.push.as_ref())
+            .and_then(Remote::distance)
+    }
+}
+
+impl Remote {
+    /// Returns the name of the branch on its remote, e.g. `main` for
+    /// `origin/main`.
+    pub fn name(&self) -> &str {
+        self.remote
+            .as_deref()
+            .and_then(|remote| self.branch.strip_prefix(remote))
+            .and_then(|name| name.strip_prefix('/'))
+            .unwrap_or(&self.branch)
+    }
+
+    /// Returns how far the local branch has diverged from the upstream.
+    pub fn distance(&self) -> Option<Distance> {
+        self.distance
+    }
+}
+
+/// Commit counts between a local branch and another branch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Distance {
+    /// Commits on the local branch only, `None` if unknown.
+    pub ahead: Option<usize>,
+    /// Commits on the other branch only, `None` if unknown.
+    pub behind: Option<usize>,
+    /// Set if counting was cut short because of `Options::max_distance`; a
+    /// count greater than `limit` then only tells that there are more than
+    /// `limit` commits, and the other count may be unknown.
+    pub limit: Option<usize>,
+}
+
+/// Converts a reference name to a string, escaping bytes that are not part
+/// of valid UTF-8 sequences as `\xNN`.
+fn escape_name(name: &[u8]) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for chunk in name.utf8_chunks() {
+        escaped.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    escaped
+}
+
+/// Abbreviates `oid` to a unique prefix of at least `length` hex digits,
+/// defaulting to `core.abbrev`.
+fn abbreviate(
+    repo: &git2::Repository,
+    oid: git2::Oid,
+    length: Option<usize>,
+) -> anyhow::Result<String> {
+    let length = match length {
+        // Like git, don't go below four digits.
+        Some(length) => length.max(4),
+        None => {
+            let short_id = repo.find_object(oid, None)?.short_id()?;
+            return Ok(short_id.as_str().unwrap_or_default().into());
+        }
+    };
+    let odb = repo.odb()?;
+    let hex = oid.to_string();
+    for length in length..hex.len() {
+        let prefix = git2::Oid::from_str(&hex[..length])?;
+        match odb.exists_prefix(prefix, length) {
+            Ok(_) => return Ok(hex[..length].into()),
+            Err(e) if e.code() == git2::ErrorCode::Ambiguous => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(hex)
+}
+
+/// Strips the `refs/heads/` or `refs/remotes/` prefix from a branch
+/// reference name.
+fn shorten_branch(refname: &[u8]) -> &[u8] {
+    refname
+        .strip_prefix(b"refs/heads/")
+        .or_else(|| refname.strip_prefix(b"refs/remotes/"))
+        .unwrap_or(refname)
+}
+
+/// Looks up a string-valued configuration entry.
+fn config_string(config: &git2::Config, key: &str) -> anyhow::Result<Option<String>> {
+    match config.get_string(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the directory shared by all worktrees of the repository.
+fn common_dir(git_dir: &Path) -> anyhow::Result<PathBuf> {
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(contents) => Ok(git_dir.join(contents.trim_end())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(git_dir.to_path_buf()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the per-user directory for the daemon socket and the cache,
+/// creating it if necessary.
+///
+/// This is `$XDG_RUNTIME_DIR/gitstat`, falling back to a directory below
+/// the system's temporary directory. Like `ssh-agent` and `tmux` do, the
+/// directory is refused unless it is owned by the current user, and
+/// inaccessible to others, as it could have been created by anyone there.
+fn runtime_dir() -> anyhow::Result<PathBuf> {
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("gitstat"),
+        None => {
+            let user = env::var("USER").unwrap_or_else(|_| "default".into());
+            env::temp_dir().join(format!("gitstat-{}", user))
+        }
+    };
+    private_dir(&dir)?;
+    Ok(dir)
+}
+
+/// Creates the directory `dir` with mode 0700 if it does not exist yet, and
+/// checks that it is private to the current user.
+pub(crate) fn private_dir(dir: &Path) -> anyhow::Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    let metadata = fs::symlink_metadata(dir)?;
+    // SAFETY: `getuid` has no preconditions, and cannot fail.
+    let uid = unsafe { libc::getuid() };
+    if !metadata.is_dir() || metadata.uid() != uid {
+        bail!(
+            "refusing to use {}: not owned by the current user",
+            dir.display()
+        );
+    }
+    if metadata.mode() & 0o777 != 0o700 {
+        bail!(
+            "refusing to use {}: accessible to other users (mode {:o})",
+            dir.display(),
+            metadata.mode() & 0o777
+        );
+    }
+    Ok(())
+}
+
+/// Returns the references to try, in order, to find the default branch, as
+/// described for `Remote::default_branch`.
+fn default_branch_candidates(
+    repo: &git2::Repository,
+    names: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let head = repo.find_reference("HEAD")?;
+    let remote = head
+        .symbolic_target()
+        .and_then(|refname| repo.branch_upstream_remote(refname).ok())
+        .and_then(|remote| remote.as_str().map(String::from))
+        .filter(|remote| remote != ".")
+        .unwrap_or_else(|| "origin".into());
+    Ok(iter::once(format!("refs/remotes/{}/HEAD", remote))
+        .chain(
+            names
+                .iter()
+                .map(|name| format!("refs/remotes/{}/{}", remote, name)),
+        )
+        .chain(names.iter().map(|name| format!("refs/heads/{}", name)))
+        .collect())
+}
+
+fn count_stashes(repo: &git2::Repository) -> anyhow::Result<usize> {
+    // The stash entries are stored in the reflog of `refs/stash`, which is
+    // empty if there is no stash.
+    Ok(repo.reflog("refs/stash")?.len())
+}
+
+/// Options for `query` and `GitInfo::from_repo`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Options {
+    /// Use this git directory, instead of searching for the repository.
+    pub git_dir: Option<PathBuf>,
+    /// Use this working tree instead of the repository's default one.
+    pub work_tree: Option<PathBuf>,
+    /// Return partial results once this time has passed.
+    pub timeout: Option<Duration>,
+    /// Branch names to try, in order, when the default branch of the remote
+    /// is not known.
+    pub default_branches: Vec<String>,
+    /// Minimum length of abbreviated commit ids, instead of `core.abbrev`.
+    pub abbrev: Option<usize>,
+    /// Count inserted and deleted lines, in addition to changed files.
+    pub diff_stats: bool,
+    /// Only check whether there are changes, instead of counting them,
+    /// leaving `GitInfo::status` undetermined.
+    pub dirty_only: bool,
+    /// The parts of `GitInfo` to determine.
+    pub parts: Parts,
+    /// Stop counting commits ahead and behind beyond this number, which
+    /// keeps that cheap on branches that diverged long ago.
+    pub max_distance: Option<usize>,
+}
+
+impl Options {
+    /// Whether changed files are to be counted.
+    fn counts_files(&self) -> bool {
+        self.parts.status && !self.dirty_only
+    }
+
+    /// Whether inserted and deleted lines are to be counted.
+    fn counts_lines(&self) -> bool {
+        self.diff_stats && self.parts.lines
+    }
+}
+
+/// The parts of `GitInfo` that take some effort to determine, and can be
+/// skipped if not needed, e.g. because the output format doesn't show them.
+///
+/// Skipped parts are left undetermined, or zero in case of `stashes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Parts {
+    /// The description of a detached `HEAD`, which may take a walk of the
+    /// history to find the most recent tag.
+    pub description: bool,
+    /// The distance to the upstream branch.
+    pub distance: bool,
+    /// The push destination, along with the distance to it.
+    pub push: bool,
+    /// The default branch, along with the distance to it.
+    pub default_branch: bool,
+    /// The counts of changed files.
+    pub status: bool,
+    /// Whether there are changes; this is derived from the counts of
+    /// changed files when these are determined.
+    pub dirty: bool,
+    /// The numbers of inserted and deleted lines, if `Options::diff_stats`
+    /// is set.
+    pub lines: bool,
+    /// The number of stash entries.
+    pub stashes: bool,
+    /// The operation in progress.
+    pub state: bool,
+}
+
+impl Parts {
+    /// All parts.
+    pub const ALL: Parts = Parts {
+        description: true,
+        distance: true,
+        push: true,
+        default_branch: true,
+        status: true,
+        dirty: true,
+        lines: true,
+        stashes: true,
+        state: true,
+    };
+
+    /// None of the parts; only `GitInfo::head`, without a description, and
+    /// the upstream branch without its distance are determined.
+    pub const NONE: Parts = Parts {
+        description: false,
+        distance: false,
+        push: false,
+        default_branch: false,
+        status: false,
+        dirty: false,
+        lines: false,
+        stashes: false,
+        state: false,
+    };
+}
+
+impl Default for Parts {
+    fn default() -> Self {
+        Parts::ALL
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            git_dir: None,
+            work_tree: None,
+            timeout: None,
+            default_branches: vec!["main".into(), "master".into()],
+            abbrev: None,
+            diff_stats: false,
+            dirty_only: false,
+            parts: Parts::ALL,
+            max_distance: Some(999),
+        }
+    }
+}
+
+/// Returns the information for the repository containing `path`, or `None`
+/// if there is no such repository.
+///
+/// When `options.timeout` is given and exhausted, the status is left
+/// undetermined, or only partially determined.
+pub fn query<P: AsRef<Path>>(path: P, options: &Options) -> anyhow::Result<Option<GitInfo>> {
+    let location = Location::new(path.as_ref(), options);
+    match options.timeout {
+        Some(timeout) => budget::info(location, options.clone(), timeout),
+        None => match location.open()? {
+            Some(repo) => Ok(Some(GitInfo::from_repo(&repo, options)?)),
+            None => Ok(None),
+        },
+    }
+}
+
+/// Specifies how to find the repository.
+#[derive(Debug, Clone)]
+struct Location {
+    /// The directory to start looking for the repository in.
+    dir: PathBuf,
+    /// Explicitly specified git directory, disabling the search.
+    git_dir: Option<PathBuf>,
+    /// Working tree to use instead of the repository's default one.
+    work_tree: Option<PathBuf>,
+}
+
+impl Location {
+    fn new(dir: &Path, options: &Options) -> Self {
+        Location {
+            dir: dir.to_path_buf(),
+            git_dir: options.git_dir.clone(),
+            work_tree: options.work_tree.clone(),
+        }
+    }
+
+    /// Opens the repository, returning `None` if there is none.
+    fn open(&self) -> anyhow::Result<Option<git2::Repository>> {
+        let repo = match &self.git_dir {
+            Some(git_dir) => git2::Repository::open(git_dir)?,
+            None => match git2::Repository::discover(&self.dir) {
+                Ok(repo) => repo,
+                Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            },
+        };
+        if let Some(work_tree) = &self.work_tree {
+            repo.set_workdir(work_tree, false)?;
+        }
+        Ok(Some(repo))
+    }
+}
+
+/// An output format.
+#[derive(Debug, Clone)]
+pub enum Format {
+    /// The JSON document produced by `json::to_string`.
+    Json,
+    /// A user-supplied or predefined template.
+    Template(Template),
+}
+
+impl Format {
+    /// Parses a format specification, which is either the name of a
+    /// predefined format or a template.
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        match spec {
+            "json" => Ok(Format::Json),
+            "zsh" => Ok(Format::Template(Template::parse(template::ZSH)?)),
+            _ => Ok(Format::Template(Template::parse(spec)?)),
+        }
+    }
+
+    /// Returns the parts of `GitInfo` this format shows.
+    pub fn parts(&self) -> Parts {
+        match self {
+            Format::Template(template) => template.parts(),
+            Format::Json => Parts::ALL,
+        }
+    }
+
+    /// Renders `info` into the complete output text.
+    pub fn render(&self, info: &GitInfo) -> anyhow::Result<String> {
+        match self {
+            Format::Template(template) => Ok(template.render(info).to_string()),
+            Format::Json => Ok(json::to_string(info)? + "\n"),
+        }
+    }
+}
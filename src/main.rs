@@ -1,252 +1,164 @@
-use std::fmt;
-
-use anyhow::anyhow;
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct Status {
-    staged: u32,
-    conflicts: u32,
-    changed: u32,
-    untracked: u32,
-}
-
-impl Status {
-    fn from_repo(repo: &git2::Repository) -> anyhow::Result<Self> {
-        let wt_changed_status = {
-            use git2::Status;
-            Status::WT_MODIFIED | Status::WT_DELETED | Status::WT_TYPECHANGE | Status::WT_RENAMED
-        };
-        let index_changed_status = {
-            use git2::Status;
-            Status::INDEX_MODIFIED
-                | Status::INDEX_DELETED
-                | Status::INDEX_TYPECHANGE
-                | Status::INDEX_RENAMED
-        };
-
-        let mut staged = 0;
-        let mut conflicts = 0;
-        let mut changed = 0;
-        let mut untracked = 0;
-
-        let mut options = git2::StatusOptions::new();
-        options.include_untracked(true);
-        for entry in repo.statuses(Some(&mut options))?.iter() {
-            let status = entry.status();
-            if status.is_conflicted() {
-                conflicts += 1;
-            }
-            if status.intersects(wt_changed_status) {
-                changed += 1;
-            }
-            if status.is_wt_new() {
-                untracked += 1;
-            }
-            if status.intersects(index_changed_status) {
-                staged += 1;
-            }
-        }
-        Ok(Status {
-            staged,
-            conflicts,
-            changed,
-            untracked,
-        })
-    }
-
-    fn display_stat(&self) -> DisplayStat<'_> {
-        return DisplayStat { status: self };
-    }
-}
-
-struct DisplayStat<'a> {
-    status: &'a Status,
-}
-
-impl<'a> fmt::Display for DisplayStat<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let status = self.status;
-        write!(
-            f,
-            "{} {} {} {}",
-            status.staged, status.conflicts, status.changed, status.untracked
-        )
-    }
-}
-
-#[derive(Debug, Clone)]
-struct BranchInfo {
-    name: String,
-    remote: Option<Remote>,
-}
-
-#[derive(Debug, Clone)]
-struct Remote {
-    branch: String,
-    distance: Option<Distance>,
-}
-
-impl Remote {
-    fn from_repo(repo: &git2::Repository, local_branch: &str) -> anyhow::Result<Option<Self>> {
-        let branch = repo.find_branch(local_branch, git2::BranchType::Local)?;
-        let upstream = match branch.upstream() {
-            Ok(upstream) => upstream,
-            Err(e) if e.code() == git2::ErrorCode::NotFound => {
-                return Ok(None);
-            }
-            Err(e) => return Err(e.into()),
-        };
-        let local_commit = branch.get().peel_to_commit()?;
-        let upstream_commit = upstream.get().peel_to_commit()?;
-        let (ahead, behind) = repo.graph_ahead_behind(local_commit.id(), upstream_commit.id())?;
-        Ok(Some(Remote {
-            branch: upstream
-                .name()?
-                .ok_or_else(|| {
-                    anyhow!(
-                        "non-UTF8 upstream branch name for local branch  {}",
-                        local_branch
-                    )
-                })?
-                .into(),
-            distance: Some(Distance { ahead, behind }),
-        }))
-    }
-}
-
-#[derive(Debug)]
-enum GitInfo {
-    Branch {
-        branch: BranchInfo,
-        status: Status,
-        oid: git2::Oid,
-    },
-    Detached {
-        oid: git2::Oid,
-        status: Status,
-    },
-    Unborn {
-        status: Status,
-    },
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use clap::builder::FalseyValueParser;
+use clap::{Parser, Subcommand};
+
+use gitstat::{cache, daemon, Format, Options};
+
+/// Git status for shell prompt integration.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Run as if started in <DIR>.
+    #[arg(short = 'C', value_name = "DIR", global = true)]
+    dir: Option<PathBuf>,
+    /// Use the repository at <PATH>, instead of searching for it.
+    #[arg(long, value_name = "PATH", global = true)]
+    git_dir: Option<PathBuf>,
+    /// Use <PATH> as the working tree.
+    #[arg(long, value_name = "PATH", global = true)]
+    work_tree: Option<PathBuf>,
+    /// Output format: `zsh`, `json`, or a template.
+    #[arg(long, default_value = "zsh", global = true)]
+    format: String,
+    /// Output partial results after this time, e.g. `150ms`.
+    #[arg(long, env = "GITSTAT_TIMEOUT", value_parser = parse_duration, global = true)]
+    timeout: Option<Duration>,
+    /// Branches to compare against if the remote's default branch is not
+    /// known.
+    #[arg(
+        long = "default-branch",
+        value_name = "NAME",
+        value_delimiter = ',',
+        default_value = "main,master",
+        global = true
+    )]
+    default_branches: Vec<String>,
+    /// Abbreviate commit ids to at least <N> hex digits, instead of
+    /// `core.abbrev`.
+    #[arg(long, value_name = "N", env = "GITSTAT_ABBREV", global = true)]
+    abbrev: Option<usize>,
+    /// Stop counting commits ahead and behind beyond <N>, showing `<N>+`;
+    /// 0 counts all of them.
+    #[arg(
+        long,
+        value_name = "N",
+        env = "GITSTAT_MAX_DISTANCE",
+        default_value_t = 999,
+        global = true
+    )]
+    max_distance: usize,
+    /// Count inserted and deleted lines of staged and unstaged changes.
+    #[arg(long, global = true)]
+    diff_stats: bool,
+    /// Only check whether there are changes, without counting them.
+    #[arg(long, global = true)]
+    dirty_only: bool,
+    /// Reuse the previous output while the repository appears unchanged.
+    #[arg(
+        long,
+        env = "GITSTAT_CACHE",
+        value_parser = FalseyValueParser::new(),
+        global = true
+    )]
+    cache: bool,
+    /// Socket to use for communication with the daemon.
+    #[arg(long, value_name = "PATH", global = true)]
+    socket: Option<PathBuf>,
+    /// Report errors on standard error.
+    #[arg(
+        long,
+        env = "GITSTAT_DEBUG",
+        value_parser = FalseyValueParser::new(),
+        global = true
+    )]
+    debug: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Subcommand)]
+enum Command {
+    /// Ask a running daemon for the status, computing it in-process if
+    /// there is none.
+    Client,
+    /// Serve status requests.
+    Daemon,
+}
+
+/// Parses a duration such as `150ms` or `2s`; plain numbers are taken as
+/// milliseconds.
+fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let invalid = || anyhow!("invalid duration '{}'", s);
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(pos) => s.split_at(pos),
+        None => (s, "ms"),
+    };
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        _ => return Err(invalid()),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid())
 }
 
-impl GitInfo {
-    fn from_repo(repo: &git2::Repository) -> anyhow::Result<Self> {
-        let head = match repo.head() {
-            Ok(head) => head,
-            Err(e) if e.code() == git2::ErrorCode::UnbornBranch => {
-                return Ok(GitInfo::Unborn {
-                    status: Status::from_repo(repo)?,
-                })
-            }
-            Err(e) => return Err(e.into()),
-        };
-        let commit = head.peel_to_commit()?;
-        if repo.head_detached()? {
-            return Ok(GitInfo::Detached {
-                oid: commit.id(),
-                status: Status::from_repo(repo)?,
-            });
-        }
-        let info = match head.shorthand() {
-            Some(name) => GitInfo::Branch {
-                branch: BranchInfo {
-                    name: name.into(),
-                    remote: Remote::from_repo(repo, name)?,
-                },
-                status: Status::from_repo(repo)?,
-                oid: commit.id(),
-            },
-            None => {
-                unimplemented!();
-            }
-        };
-        Ok(info)
+fn run(args: Args) -> anyhow::Result<()> {
+    if let Some(dir) = &args.dir {
+        env::set_current_dir(dir)
+            .with_context(|| format!("cannot change to '{}'", dir.display()))?;
     }
-    fn prompt(&self) -> Prompt<'_> {
-        Prompt { info: self }
+    // The default location is only created and checked when needed.
+    let socket = args.socket;
+    let socket = || socket.clone().map_or_else(daemon::default_socket_path, Ok);
+    if args.command == Some(Command::Daemon) {
+        return daemon::serve(&socket()?);
     }
-}
-
-struct Prompt<'a> {
-    info: &'a GitInfo,
-}
-
-impl<'a> fmt::Display for Prompt<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use GitInfo::*;
-        match self.info {
-            Branch { branch, status, .. } => {
-                let (ahead, behind) = if let Some(remote) = &branch.remote {
-                    remote.distance().map_or((0, 0), |d| d.as_pair())
-                } else {
-                    (0, 0)
-                };
-                write!(
-                    f,
-                    "{} {} {} {}",
-                    branch.name,
-                    ahead,
-                    behind,
-                    status.display_stat(),
-                )?;
-            }
-            Detached { oid, status } => {
-                let short_oid: String = oid.to_string().chars().take(6).collect();
-                write!(f, ":{} 0 0 {}", short_oid, status.display_stat())?;
-            }
-            Unborn { status } => {
-                write!(f, "? 0 0 {}", status.display_stat())?;
-            }
+    let format = Format::parse(&args.format)?;
+    // Make paths absolute, so they can be passed to the daemon.
+    let cwd = env::current_dir()?;
+    let options = Options {
+        git_dir: args.git_dir.map(|path| cwd.join(path)),
+        work_tree: args.work_tree.map(|path| cwd.join(path)),
+        timeout: args.timeout,
+        default_branches: args.default_branches,
+        abbrev: args.abbrev,
+        diff_stats: args.diff_stats,
+        dirty_only: args.dirty_only,
+        parts: format.parts(),
+        max_distance: Some(args.max_distance).filter(|&max| max != 0),
+    };
+    if args.command == Some(Command::Client) {
+        if let Some(output) = daemon::query(&socket()?, &cwd, &options, &args.format)? {
+            print!("{}", output);
+            return Ok(());
         }
-        Ok(())
     }
-}
-
-impl Remote {
-    fn distance(&self) -> Option<Distance> {
-        self.distance
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-struct Distance {
-    ahead: usize,
-    behind: usize,
-}
-
-impl Distance {
-    fn as_pair(self) -> (usize, usize) {
-        (self.ahead, self.behind)
-    }
-}
-
-fn info() -> anyhow::Result<Option<GitInfo>> {
-    let repo = match git2::Repository::discover(".") {
-        Ok(repo) => repo,
-        Err(e) if e.code() == git2::ErrorCode::NotFound => {
-            return Ok(None);
+    let output = if args.cache {
+        cache::query(&cache::default_dir()?, &cwd, &options, &args.format)?
+    } else {
+        match gitstat::query(&cwd, &options)? {
+            Some(info) => Some(format.render(&info)?),
+            None => None,
         }
-        Err(e) => return Err(e.into()),
     };
-    Ok(Some(GitInfo::from_repo(&repo)?))
+    if let Some(output) = output {
+        print!("{}", output);
+    }
+    Ok(())
 }
 
-// TODO: use environment variable or command-line option here
-const DEBUG: bool = true;
-
 fn main() {
-    let rc = match info() {
-        Ok(Some(info)) => {
-            print!("{}", info.prompt());
-            0
-        }
-        Ok(None) => {
-            0
-        }
+    let args = Args::parse();
+    let debug = args.debug;
+    let rc = match run(args) {
+        Ok(()) => 0,
         Err(e) => {
-            if DEBUG {
-                eprintln!("error: {}", e);
+            if debug {
+                eprintln!("error: {:#}", e);
             }
             1
         }
@@ -0,0 +1,79 @@
+//! Resolution of the push destination of a branch, known as `@{push}` to
+//! git.
+//!
+//! The branch is pushed to `branch.<name>.pushRemote`, `remote.pushDefault`
+//! or `branch.<name>.remote`, in that order of preference, falling back to
+//! `origin`. Which branch on the remote it is pushed to is determined by
+//! the remote's push refspecs, or by `push.default` in their absence. The
+//! result is the remote-tracking branch that corresponds to that
+//! destination, so comparing against it tells what hasn't been pushed yet.
+
+use crate::config_string;
+
+/// Returns the name of the remote-tracking reference corresponding to the
+/// push destination of the local branch `refname`, or `None` if the branch
+/// would not be pushed anywhere.
+pub fn tracking_ref(repo: &git2::Repository, refname: &str) -> anyhow::Result<Option<String>> {
+    let name = match refname.strip_prefix("refs/heads/") {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let config = repo.config()?.snapshot()?;
+    let fetch_remote = config_string(&config, &format!("branch.{}.remote", name))?;
+    let push_remote = match config_string(&config, &format!("branch.{}.pushRemote", name))? {
+        Some(remote) => remote,
+        None => match config_string(&config, "remote.pushDefault")? {
+            Some(remote) => remote,
+            None => fetch_remote.clone().unwrap_or_else(|| "origin".into()),
+        },
+    };
+    // Branches pushed to "." end up in this very repository.
+    let remote = if push_remote == "." {
+        None
+    } else {
+        match repo.find_remote(&push_remote) {
+            Ok(remote) => Some(remote),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+    };
+    let push_specs: Vec<_> = remote
+        .iter()
+        .flat_map(|remote| remote.refspecs())
+        .filter(|spec| spec.direction() == git2::Direction::Push)
+        .collect();
+    let destination = if !push_specs.is_empty() {
+        let spec = push_specs.iter().find(|spec| spec.src_matches(refname));
+        match spec.and_then(|spec| spec.transform(refname).ok()) {
+            Some(destination) => destination.as_str().map(String::from),
+            None => return Ok(None),
+        }
+    } else {
+        let merge = config_string(&config, &format!("branch.{}.merge", name))?;
+        let triangular = fetch_remote.as_deref().unwrap_or("origin") != push_remote;
+        let mode = config_string(&config, "push.default")?;
+        match mode.as_deref().unwrap_or("simple") {
+            "current" | "matching" => Some(refname.into()),
+            "upstream" | "tracking" if !triangular => merge,
+            // Without a triangular setup, `simple` refuses to push to an
+            // upstream branch of a different name.
+            "simple" if triangular || merge.as_deref() == Some(refname) => Some(refname.into()),
+            _ => None,
+        }
+    };
+    let destination = match destination {
+        Some(destination) => destination,
+        None => return Ok(None),
+    };
+    let remote = match remote {
+        Some(remote) => remote,
+        None => return Ok(Some(destination)),
+    };
+    let tracking = remote
+        .refspecs()
+        .filter(|spec| spec.direction() == git2::Direction::Fetch)
+        .find(|spec| spec.src_matches(&destination))
+        .and_then(|spec| spec.transform(&destination).ok())
+        .and_then(|tracking| tracking.as_str().map(String::from));
+    Ok(tracking)
+}
@@ -0,0 +1,98 @@
+//! In-progress repository operations, such as merges and rebases.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use git2::RepositoryState;
+
+/// An operation in progress, such as a merge or rebase.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    /// The kind of operation.
+    pub kind: RepositoryState,
+    /// Step progress of a rebase or `git am` session, if known.
+    pub progress: Option<Progress>,
+    /// The branch being rebased, if any.
+    pub branch: Option<String>,
+}
+
+/// How far a multi-step operation has advanced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// The current step, counting from 1.
+    pub step: usize,
+    /// The total number of steps.
+    pub total: usize,
+}
+
+impl State {
+    /// Returns the operation in progress, or `None` if there is none.
+    pub fn from_repo(repo: &git2::Repository) -> anyhow::Result<Option<Self>> {
+        use RepositoryState::*;
+        let kind = repo.state();
+        let git_dir = repo.path();
+        let (progress, branch) = match kind {
+            Clean => return Ok(None),
+            RebaseInteractive | RebaseMerge => {
+                let dir = git_dir.join("rebase-merge");
+                (read_progress(&dir, "msgnum", "end")?, read_head_name(&dir)?)
+            }
+            Rebase | ApplyMailbox | ApplyMailboxOrRebase => {
+                let dir = git_dir.join("rebase-apply");
+                (read_progress(&dir, "next", "last")?, read_head_name(&dir)?)
+            }
+            _ => (None, None),
+        };
+        Ok(Some(State {
+            kind,
+            progress,
+            branch,
+        }))
+    }
+
+    /// Returns a short, stable identifier for the kind of operation.
+    pub fn name(&self) -> &'static str {
+        use RepositoryState::*;
+        match self.kind {
+            Clean => "clean",
+            Merge => "merge",
+            Revert => "revert",
+            RevertSequence => "revert-sequence",
+            CherryPick => "cherry-pick",
+            CherryPickSequence => "cherry-pick-sequence",
+            Bisect => "bisect",
+            Rebase => "rebase",
+            RebaseInteractive => "rebase-interactive",
+            RebaseMerge => "rebase-merge",
+            ApplyMailbox => "am",
+            ApplyMailboxOrRebase => "am-or-rebase",
+        }
+    }
+}
+
+fn read_file(path: &Path) -> anyhow::Result<Option<String>> {
+    match fs::read(path) {
+        // `head-name` holds a reference name, which need not be UTF-8.
+        Ok(contents) => Ok(Some(crate::escape_name(&contents).trim().into())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read_progress(
+    dir: &Path,
+    step_file: &str,
+    total_file: &str,
+) -> anyhow::Result<Option<Progress>> {
+    let step = read_file(&dir.join(step_file))?.and_then(|s| s.parse().ok());
+    let total = read_file(&dir.join(total_file))?.and_then(|s| s.parse().ok());
+    Ok(step
+        .zip(total)
+        .map(|(step, total)| Progress { step, total }))
+}
+
+fn read_head_name(dir: &Path) -> anyhow::Result<Option<String>> {
+    Ok(read_file(&dir.join("head-name"))?
+        .and_then(|name| name.strip_prefix("refs/heads/").map(Into::into)))
+}
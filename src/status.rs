@@ -0,0 +1,376 @@
+//! Counting changed files in the index and working tree.
+
+use crate::config_string;
+
+/// Counts of changed files in the index and working tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    /// Number of files with staged changes.
+    pub staged: u32,
+    /// Number of files with merge conflicts.
+    pub conflicts: u32,
+    /// Merge conflicts, by kind.
+    pub unmerged: Conflicts,
+    /// Number of files with unstaged changes.
+    pub changed: u32,
+    /// Number of untracked files, `None` if not determined.
+    pub untracked: Option<u32>,
+    /// Staged changes, by kind.
+    pub index: Changes,
+    /// Unstaged changes, by kind. New files in the working tree are
+    /// untracked, so `added` equals `untracked`, or is zero if untracked
+    /// files were not looked for.
+    pub worktree: Changes,
+}
+
+/// Numbers of files changed in a particular way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Changes {
+    /// New files.
+    pub added: u32,
+    /// Files with modified contents.
+    pub modified: u32,
+    /// Removed files.
+    pub deleted: u32,
+    /// Files moved to a different path.
+    pub renamed: u32,
+    /// Files that changed between regular file, symlink and submodule.
+    pub typechanged: u32,
+    /// Number of inserted lines, if counted.
+    pub insertions: Option<usize>,
+    /// Number of deleted lines, if counted.
+    pub deletions: Option<usize>,
+}
+
+impl Changes {
+    /// Counts a status entry, using the flags for the respective side.
+    fn add(&mut self, status: git2::Status, flags: &ChangeFlags) {
+        let counters = [
+            (flags.added, &mut self.added),
+            (flags.modified, &mut self.modified),
+            (flags.deleted, &mut self.deleted),
+            (flags.renamed, &mut self.renamed),
+            (flags.typechanged, &mut self.typechanged),
+        ];
+        for (flag, counter) in counters {
+            if status.contains(flag) {
+                *counter += 1;
+            }
+        }
+    }
+}
+
+/// The settings from the repository configuration that affect the status,
+/// as far as they concern the counts.
+///
+/// `status.submoduleSummary` only adds a summary of submodule commits to
+/// the output of `git status`, so it is of no concern.
+pub(crate) struct Settings {
+    /// `status.showUntrackedFiles`.
+    untracked: Untracked,
+    /// `diff.ignoreSubmodules`, if set.
+    ignore_submodules: Option<git2::SubmoduleIgnore>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Untracked {
+    /// Untracked files are not looked for.
+    No,
+    /// Untracked directories are reported as a whole.
+    Normal,
+    /// Files in untracked directories are reported individually.
+    All,
+}
+
+impl Settings {
+    pub fn from_repo(repo: &git2::Repository) -> anyhow::Result<Self> {
+        let config = repo.config()?;
+        // Like git, fall back to the defaults for unknown values.
+        let untracked = match config_string(&config, "status.showUntrackedFiles")?.as_deref() {
+            Some("no") | Some("false") => Untracked::No,
+            Some("all") => Untracked::All,
+            _ => Untracked::Normal,
+        };
+        let ignore_submodules = match config_string(&config, "diff.ignoreSubmodules")?.as_deref() {
+            Some("none") => Some(git2::SubmoduleIgnore::None),
+            Some("untracked") => Some(git2::SubmoduleIgnore::Untracked),
+            Some("dirty") => Some(git2::SubmoduleIgnore::Dirty),
+            Some("all") => Some(git2::SubmoduleIgnore::All),
+            _ => None,
+        };
+        Ok(Settings {
+            untracked,
+            ignore_submodules,
+        })
+    }
+
+    /// Returns the options used for obtaining the status entries.
+    pub fn options(&self) -> git2::StatusOptions {
+        let mut options = git2::StatusOptions::new();
+        // Like `git status`, only staged renames are detected.
+        options
+            .include_untracked(self.show_untracked())
+            .recurse_untracked_dirs(self.recurse_untracked_dirs())
+            .exclude_submodules(self.ignore_submodules == Some(git2::SubmoduleIgnore::All))
+            .renames_head_to_index(true);
+        options
+    }
+
+    /// Whether untracked files are looked for.
+    pub fn show_untracked(&self) -> bool {
+        self.untracked != Untracked::No
+    }
+
+    /// Whether files in untracked directories are reported individually.
+    pub fn recurse_untracked_dirs(&self) -> bool {
+        self.untracked == Untracked::All
+    }
+
+    /// Returns the status of `entry`, with changes within submodules
+    /// disregarded as configured.
+    ///
+    /// libgit2 only honors the per-submodule `submodule.<name>.ignore`, so
+    /// the status of a modified submodule is determined anew.
+    pub fn entry_status(
+        &self,
+        repo: &git2::Repository,
+        entry: &git2::StatusEntry,
+    ) -> anyhow::Result<git2::Status> {
+        let status = entry.status();
+        if self.ignore_submodules.is_none() || !status.is_wt_modified() {
+            return Ok(status);
+        }
+        let is_submodule = entry
+            .index_to_workdir()
+            .is_some_and(|delta| delta.old_file().mode() == git2::FileMode::Commit);
+        let path = match entry.path() {
+            Some(path) if is_submodule => path,
+            _ => return Ok(status),
+        };
+        if self.submodule_modified(repo, path)? {
+            Ok(status)
+        } else {
+            Ok(status - git2::Status::WT_MODIFIED)
+        }
+    }
+
+    /// Checks whether the working tree of the submodule at `path` differs
+    /// from the commit recorded in the index, as far as not disregarded.
+    pub fn submodule_modified(&self, repo: &git2::Repository, path: &str) -> anyhow::Result<bool> {
+        let ignore = self
+            .ignore_submodules
+            .unwrap_or(git2::SubmoduleIgnore::Unspecified);
+        let modified = {
+            use git2::SubmoduleStatus as S;
+            S::WD_MODIFIED | S::WD_INDEX_MODIFIED | S::WD_WD_MODIFIED | S::WD_UNTRACKED
+        };
+        Ok(repo.submodule_status(path, ignore)?.intersects(modified))
+    }
+}
+
+/// Numbers of conflicted files, classified like `git status` does by the
+/// sides of the merge the file is present on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Conflicts {
+    /// Files changed on both sides.
+    pub both_modified: u32,
+    /// Files added on both sides, with different contents.
+    pub both_added: u32,
+    /// Files deleted on both sides; both were renamed differently.
+    pub both_deleted: u32,
+    /// Files added on our side only.
+    pub added_by_us: u32,
+    /// Files added on their side only.
+    pub added_by_them: u32,
+    /// Files deleted on our side, but changed on theirs.
+    pub deleted_by_us: u32,
+    /// Files deleted on their side, but changed on ours.
+    pub deleted_by_them: u32,
+}
+
+impl Conflicts {
+    /// Classifies the conflict entries of the repository's index.
+    pub fn from_repo(repo: &git2::Repository) -> anyhow::Result<Self> {
+        let mut conflicts = Conflicts::default();
+        for conflict in repo.index()?.conflicts()? {
+            let conflict = conflict?;
+            let sides = (
+                conflict.ancestor.is_some(),
+                conflict.our.is_some(),
+                conflict.their.is_some(),
+            );
+            let counter = match sides {
+                (true, true, true) => &mut conflicts.both_modified,
+                (false, true, true) => &mut conflicts.both_added,
+                (true, false, false) => &mut conflicts.both_deleted,
+                (false, true, false) => &mut conflicts.added_by_us,
+                (false, false, true) => &mut conflicts.added_by_them,
+                (true, false, true) => &mut conflicts.deleted_by_us,
+                (true, true, false) => &mut conflicts.deleted_by_them,
+                (false, false, false) => continue,
+            };
+            *counter += 1;
+        }
+        Ok(conflicts)
+    }
+}
+
+/// The status flags corresponding to the fields of `Changes`.
+struct ChangeFlags {
+    added: git2::Status,
+    modified: git2::Status,
+    deleted: git2::Status,
+    renamed: git2::Status,
+    typechanged: git2::Status,
+}
+
+const INDEX_FLAGS: ChangeFlags = ChangeFlags {
+    added: git2::Status::INDEX_NEW,
+    modified: git2::Status::INDEX_MODIFIED,
+    deleted: git2::Status::INDEX_DELETED,
+    renamed: git2::Status::INDEX_RENAMED,
+    typechanged: git2::Status::INDEX_TYPECHANGE,
+};
+
+const WORKTREE_FLAGS: ChangeFlags = ChangeFlags {
+    added: git2::Status::WT_NEW,
+    modified: git2::Status::WT_MODIFIED,
+    deleted: git2::Status::WT_DELETED,
+    renamed: git2::Status::WT_RENAMED,
+    typechanged: git2::Status::WT_TYPECHANGE,
+};
+
+impl Status {
+    /// Computes the status of the repository's working tree.
+    pub fn from_repo(repo: &git2::Repository) -> anyhow::Result<Self> {
+        let settings = Settings::from_repo(repo)?;
+        let statuses = repo.statuses(Some(&mut settings.options()))?;
+        Self::from_statuses(repo, &settings, &statuses)
+    }
+
+    /// Like `from_repo`, but skips looking for untracked files, which is
+    /// typically the most expensive part.
+    pub fn from_repo_without_untracked(repo: &git2::Repository) -> anyhow::Result<Self> {
+        let settings = Settings::from_repo(repo)?;
+        let mut options = settings.options();
+        options.include_untracked(false);
+        let statuses = repo.statuses(Some(&mut options))?;
+        Ok(Status {
+            untracked: None,
+            ..Self::from_statuses(repo, &settings, &statuses)?
+        })
+    }
+
+    fn from_statuses(
+        repo: &git2::Repository,
+        settings: &Settings,
+        statuses: &git2::Statuses,
+    ) -> anyhow::Result<Self> {
+        let entries = statuses
+            .iter()
+            .map(|entry| settings.entry_status(repo, &entry))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Self::from_entries(entries).classify_conflicts(repo)
+    }
+
+    /// Fills in `unmerged` from the index, which the status entries carry
+    /// no information about.
+    pub(crate) fn classify_conflicts(self, repo: &git2::Repository) -> anyhow::Result<Self> {
+        if self.conflicts == 0 {
+            return Ok(self);
+        }
+        Ok(Status {
+            unmerged: Conflicts::from_repo(repo)?,
+            ..self
+        })
+    }
+
+    /// Counts the inserted and deleted lines of the staged changes, compared
+    /// to `HEAD`, and of the unstaged changes, compared to the index. This
+    /// diffs the contents of all changed files, so it is considerably more
+    /// expensive than obtaining the status.
+    pub fn count_lines(self, repo: &git2::Repository) -> anyhow::Result<Self> {
+        let head = match repo.head() {
+            Ok(head) => Some(head.peel_to_tree()?),
+            Err(e) if e.code() == git2::ErrorCode::UnbornBranch => None,
+            Err(e) => return Err(e.into()),
+        };
+        let staged = repo
+            .diff_tree_to_index(head.as_ref(), None, None)?
+            .stats()?;
+        let unstaged = repo.diff_index_to_workdir(None, None)?.stats()?;
+        Ok(Status {
+            index: Changes {
+                insertions: Some(staged.insertions()),
+                deletions: Some(staged.deletions()),
+                ..self.index
+            },
+            worktree: Changes {
+                insertions: Some(unstaged.insertions()),
+                deletions: Some(unstaged.deletions()),
+                ..self.worktree
+            },
+            ..self
+        })
+    }
+
+    /// Tallies up the given status entries, as obtained from
+    /// `git2::Repository::statuses`. As the entries don't tell, conflicts
+    /// are not classified by kind.
+    pub fn from_entries<I>(entries: I) -> Self
+    where
+        I: IntoIterator<Item = git2::Status>,
+    {
+        let wt_changed_status = {
+            use git2::Status;
+            Status::WT_MODIFIED | Status::WT_DELETED | Status::WT_TYPECHANGE | Status::WT_RENAMED
+        };
+        let index_changed_status = {
+            use git2::Status;
+            Status::INDEX_NEW
+                | Status::INDEX_MODIFIED
+                | Status::INDEX_DELETED
+                | Status::INDEX_TYPECHANGE
+                | Status::INDEX_RENAMED
+        };
+
+        let mut staged = 0;
+        let mut conflicts = 0;
+        let mut changed = 0;
+        let mut untracked = 0;
+        let mut index = Changes::default();
+        let mut worktree = Changes::default();
+
+        for status in entries {
+            index.add(status, &INDEX_FLAGS);
+            worktree.add(status, &WORKTREE_FLAGS);
+            if status.is_conflicted() {
+                conflicts += 1;
+            }
+            if status.intersects(wt_changed_status) {
+                changed += 1;
+            }
+            if status.is_wt_new() {
+                untracked += 1;
+            }
+            if status.intersects(index_changed_status) {
+                staged += 1;
+            }
+        }
+        Status {
+            staged,
+            conflicts,
+            unmerged: Conflicts::default(),
+            changed,
+            untracked: Some(untracked),
+            index,
+            worktree,
+        }
+    }
+
+    /// Checks whether there are no changes at all; an undetermined untracked
+    /// count is not considered clean.
+    pub fn is_clean(&self) -> bool {
+        self.staged == 0 && self.conflicts == 0 && self.changed == 0 && self.untracked == Some(0)
+    }
+}
@@ -0,0 +1,636 @@
+//! User-defined output templates.
+//!
+//! A template is literal text interspersed with placeholders:
+//!
+//! - `{name}` is replaced by the value of the field `name`.
+//! - `{?name:text}` renders `text` only if the field is non-zero (or
+//!   non-empty, for string fields).
+//! - `{!name:text}` renders `text` only if the field is zero (or empty).
+//! - A backslash escapes the following character, so `\{`, `\}` and `\\`
+//!   produce literal braces and backslashes.
+//!
+//! The `text` of a conditional section is itself a template, so sections may
+//! contain placeholders and further sections.
+//!
+//! Values that could not be determined within the time budget are rendered
+//! as `?`, and count as non-zero.
+
+use std::fmt;
+
+use anyhow::{anyhow, bail};
+
+use crate::{Dirty, Distance, GitInfo, Head, Parts, Status, Upstream};
+
+/// Template reproducing the traditional `gitstatus.py` output line.
+pub const ZSH: &str =
+    "{branch} {ahead} {behind} {staged} {conflicts} {changed} {untracked} {stashes}";
+
+/// A parsed template.
+#[derive(Debug, Clone)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Literal(String),
+    Field(Field),
+    Section {
+        field: Field,
+        non_zero: bool,
+        body: Vec<Node>,
+    },
+}
+
+/// A field that can be referenced from a template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Branch,
+    Head,
+    Oid,
+    ShortOid,
+    Ahead,
+    Behind,
+    PushAhead,
+    PushBehind,
+    Upstream,
+    UpstreamRemote,
+    UpstreamBranch,
+    UpstreamMismatch,
+    Gone,
+    DefaultBranch,
+    DefaultAhead,
+    DefaultBehind,
+    Staged,
+    Conflicts,
+    UnmergedBothModified,
+    UnmergedBothAdded,
+    UnmergedBothDeleted,
+    UnmergedAddedByUs,
+    UnmergedAddedByThem,
+    UnmergedDeletedByUs,
+    UnmergedDeletedByThem,
+    Changed,
+    Untracked,
+    IndexAdded,
+    IndexModified,
+    IndexDeleted,
+    IndexRenamed,
+    IndexTypechanged,
+    IndexInsertions,
+    IndexDeletions,
+    WorktreeAdded,
+    WorktreeModified,
+    WorktreeDeleted,
+    WorktreeRenamed,
+    WorktreeTypechanged,
+    WorktreeInsertions,
+    WorktreeDeletions,
+    Clean,
+    Dirty,
+    IndexDirty,
+    WorktreeDirty,
+    HasUntracked,
+    Stashes,
+    State,
+    Step,
+    Total,
+    RebaseBranch,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        use Field::*;
+        let field = match name {
+            "branch" => Branch,
+            "head" => Head,
+            "oid" => Oid,
+            "short_oid" => ShortOid,
+            "ahead" => Ahead,
+            "behind" => Behind,
+            "push_ahead" => PushAhead,
+            "push_behind" => PushBehind,
+            "upstream" => Upstream,
+            "upstream_remote" => UpstreamRemote,
+            "upstream_branch" => UpstreamBranch,
+            "upstream_mismatch" => UpstreamMismatch,
+            "gone" => Gone,
+            "default_branch" => DefaultBranch,
+            "default_ahead" => DefaultAhead,
+            "default_behind" => DefaultBehind,
+            "staged" => Staged,
+            "conflicts" => Conflicts,
+            "unmerged_both_modified" => UnmergedBothModified,
+            "unmerged_both_added" => UnmergedBothAdded,
+            "unmerged_both_deleted" => UnmergedBothDeleted,
+            "unmerged_added_by_us" => UnmergedAddedByUs,
+            "unmerged_added_by_them" => UnmergedAddedByThem,
+            "unmerged_deleted_by_us" => UnmergedDeletedByUs,
+            "unmerged_deleted_by_them" => UnmergedDeletedByThem,
+            "changed" => Changed,
+            "untracked" => Untracked,
+            "index_added" => IndexAdded,
+            "index_modified" => IndexModified,
+            "index_deleted" => IndexDeleted,
+            "index_renamed" => IndexRenamed,
+            "index_typechanged" => IndexTypechanged,
+            "index_insertions" => IndexInsertions,
+            "index_deletions" => IndexDeletions,
+            "worktree_added" => WorktreeAdded,
+            "worktree_modified" => WorktreeModified,
+            "worktree_deleted" => WorktreeDeleted,
+            "worktree_renamed" => WorktreeRenamed,
+            "worktree_typechanged" => WorktreeTypechanged,
+            "worktree_insertions" => WorktreeInsertions,
+            "worktree_deletions" => WorktreeDeletions,
+            "clean" => Clean,
+            "dirty" => Dirty,
+            "index_dirty" => IndexDirty,
+            "worktree_dirty" => WorktreeDirty,
+            "has_untracked" => HasUntracked,
+            "stashes" => Stashes,
+            "state" => State,
+            "step" => Step,
+            "total" => Total,
+            "rebase_branch" => RebaseBranch,
+            _ => return None,
+        };
+        Some(field)
+    }
+
+    /// Marks the parts of `GitInfo` the field's value is taken from.
+    fn add_parts(self, parts: &mut Parts) {
+        use Field::*;
+        match self {
+            Branch => parts.description = true,
+            Head | Oid | ShortOid | Upstream | UpstreamRemote | UpstreamBranch
+            | UpstreamMismatch | Gone => {}
+            Ahead | Behind => parts.distance = true,
+            PushAhead | PushBehind => parts.push = true,
+            DefaultBranch | DefaultAhead | DefaultBehind => parts.default_branch = true,
+            IndexInsertions | IndexDeletions | WorktreeInsertions | WorktreeDeletions => {
+                parts.status = true;
+                parts.lines = true;
+            }
+            Clean | Dirty | IndexDirty | WorktreeDirty | HasUntracked => parts.dirty = true,
+            Stashes => parts.stashes = true,
+            State | Step | Total | RebaseBranch => parts.state = true,
+            Staged
+            | Conflicts
+            | UnmergedBothModified
+            | UnmergedBothAdded
+            | UnmergedBothDeleted
+            | UnmergedAddedByUs
+            | UnmergedAddedByThem
+            | UnmergedDeletedByUs
+            | UnmergedDeletedByThem
+            | Changed
+            | Untracked
+            | IndexAdded
+            | IndexModified
+            | IndexDeleted
+            | IndexRenamed
+            | IndexTypechanged
+            | WorktreeAdded
+            | WorktreeModified
+            | WorktreeDeleted
+            | WorktreeRenamed
+            | WorktreeTypechanged => parts.status = true,
+        }
+    }
+
+    fn value(self, info: &GitInfo) -> Value {
+        let status = info.status.as_ref();
+        let count = |count: fn(&Status) -> u32| {
+            status.map_or(Value::Unknown, |status| Value::Num(count(status) as usize))
+        };
+        let lines = |lines: fn(&Status) -> Option<usize>| {
+            status.and_then(lines).map_or(Value::Unknown, Value::Num)
+        };
+        let dirty = info.dirty.as_ref();
+        let flag = |flag: fn(&Dirty) -> Option<bool>| {
+            dirty.and_then(flag).map_or(Value::Unknown, Value::Bool)
+        };
+        let state = info.state.as_ref();
+        let progress = state.and_then(|state| state.progress);
+        let upstream = info.upstream();
+        let default = info.default_branch.as_ref();
+        let default_distance = default.and_then(|remote| remote.distance);
+        match self {
+            Field::Branch => Value::Str(match &info.head {
+                Head::Branch { branch, .. } => branch.name.clone(),
+                Head::Detached {
+                    description: Some(description),
+                    ..
+                } => format!(":{}", description),
+                Head::Detached { short_id, .. } => format!(":{}", short_id),
+                Head::Unborn => "?".into(),
+            }),
+            Field::Head => Value::Str(
+                match info.head {
+                    Head::Branch { .. } => "branch",
+                    Head::Detached { .. } => "detached",
+                    Head::Unborn => "unborn",
+                }
+                .into(),
+            ),
+            Field::Oid => Value::Str(
+                info.head
+                    .oid()
+                    .map(|oid| oid.to_string())
+                    .unwrap_or_default(),
+            ),
+            Field::ShortOid => Value::Str(info.head.short_id().unwrap_or_default().into()),
+            Field::Ahead => count_commits(info.distance(), |d| d.ahead),
+            Field::Behind => count_commits(info.distance(), |d| d.behind),
+            Field::PushAhead => count_commits(info.push_distance(), |d| d.ahead),
+            Field::PushBehind => count_commits(info.push_distance(), |d| d.behind),
+            Field::Upstream => {
+                Value::Str(upstream.map(Upstream::branch).unwrap_or_default().into())
+            }
+            Field::UpstreamRemote => Value::Str(
+                upstream
+                    .and_then(Upstream::remote)
+                    .unwrap_or_default()
+                    .into(),
+            ),
+            Field::UpstreamBranch => {
+                Value::Str(upstream.map(Upstream::name).unwrap_or_default().into())
+            }
+            Field::UpstreamMismatch => Value::Bool(
+                info.branch()
+                    .is_some_and(|branch| branch.upstream_mismatch()),
+            ),
+            Field::Gone => Value::Bool(matches!(upstream, Some(Upstream::Gone { .. }))),
+            Field::DefaultBranch => Value::Str(
+                default
+                    .map(|remote| remote.branch.clone())
+                    .unwrap_or_default(),
+            ),
+            Field::DefaultAhead => count_commits(default_distance, |d| d.ahead),
+            Field::DefaultBehind => count_commits(default_distance, |d| d.behind),
+            Field::Staged => count(|status| status.staged),
+            Field::Conflicts => count(|status| status.conflicts),
+            Field::UnmergedBothModified => count(|status| status.unmerged.both_modified),
+            Field::UnmergedBothAdded => count(|status| status.unmerged.both_added),
+            Field::UnmergedBothDeleted => count(|status| status.unmerged.both_deleted),
+            Field::UnmergedAddedByUs => count(|status| status.unmerged.added_by_us),
+            Field::UnmergedAddedByThem => count(|status| status.unmerged.added_by_them),
+            Field::UnmergedDeletedByUs => count(|status| status.unmerged.deleted_by_us),
+            Field::UnmergedDeletedByThem => count(|status| status.unmerged.deleted_by_them),
+            Field::Changed => count(|status| status.changed),
+            Field::Untracked => status
+                .and_then(|status| status.untracked)
+                .map_or(Value::Unknown, |n| Value::Num(n as usize)),
+            Field::IndexAdded => count(|status| status.index.added),
+            Field::IndexModified => count(|status| status.index.modified),
+            Field::IndexDeleted => count(|status| status.index.deleted),
+            Field::IndexRenamed => count(|status| status.index.renamed),
+            Field::IndexTypechanged => count(|status| status.index.typechanged),
+            Field::IndexInsertions => lines(|status| status.index.insertions),
+            Field::IndexDeletions => lines(|status| status.index.deletions),
+            Field::WorktreeAdded => count(|status| status.worktree.added),
+            Field::WorktreeModified => count(|status| status.worktree.modified),
+            Field::WorktreeDeleted => count(|status| status.worktree.deleted),
+            Field::WorktreeRenamed => count(|status| status.worktree.renamed),
+            Field::WorktreeTypechanged => count(|status| status.worktree.typechanged),
+            Field::WorktreeInsertions => lines(|status| status.worktree.insertions),
+            Field::WorktreeDeletions => lines(|status| status.worktree.deletions),
+            Field::Clean => Value::Bool(dirty.and_then(Dirty::any) == Some(false)),
+            Field::Dirty => flag(Dirty::any),
+            Field::IndexDirty => flag(|dirty| Some(dirty.index)),
+            Field::WorktreeDirty => flag(|dirty| Some(dirty.worktree)),
+            Field::HasUntracked => flag(|dirty| dirty.untracked),
+            Field::Stashes => Value::Num(info.stashes),
+            Field::State => Value::Str(state.map(|state| state.name()).unwrap_or_default().into()),
+            Field::Step => Value::Num(progress.map_or(0, |p| p.step)),
+            Field::Total => Value::Num(progress.map_or(0, |p| p.total)),
+            Field::RebaseBranch => Value::Str(
+                state
+                    .and_then(|state| state.branch.clone())
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+/// Returns a commit count taken from `distance`, rendered as e.g. `999+`
+/// if it exceeds the limit.
+fn count_commits(distance: Option<Distance>, count: fn(&Distance) -> Option<usize>) -> Value {
+    let distance = match distance {
+        Some(distance) => distance,
+        None => return Value::Num(0),
+    };
+    match (count(&distance), distance.limit) {
+        (Some(count), Some(limit)) if count > limit => Value::Str(format!("{}+", limit)),
+        (Some(count), _) => Value::Num(count),
+        (None, _) => Value::Unknown,
+    }
+}
+
+enum Value {
+    Str(String),
+    Num(usize),
+    Bool(bool),
+    /// A value that could not be determined in time.
+    Unknown,
+}
+
+impl Value {
+    fn is_non_zero(&self) -> bool {
+        match self {
+            Value::Str(s) => !s.is_empty(),
+            Value::Num(n) => *n != 0,
+            Value::Bool(b) => *b,
+            Value::Unknown => true,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Str(s) => f.write_str(s),
+            Value::Num(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Unknown => f.write_str("?"),
+        }
+    }
+}
+
+impl Template {
+    /// Parses the template `source`, rejecting unknown fields.
+    pub fn parse(source: &str) -> anyhow::Result<Self> {
+        let mut parser = Parser {
+            source,
+            pos: 0,
+            depth: 0,
+        };
+        let nodes = parser.nodes()?;
+        Ok(Template { nodes })
+    }
+
+    /// Returns the parts of `GitInfo` the template refers to.
+    pub fn parts(&self) -> Parts {
+        let mut parts = Parts::NONE;
+        add_parts(&self.nodes, &mut parts);
+        parts
+    }
+
+    /// Returns the template applied to `info`, for use with `Display`.
+    pub fn render<'a>(&'a self, info: &'a GitInfo) -> Rendered<'a> {
+        Rendered {
+            nodes: &self.nodes,
+            info,
+        }
+    }
+}
+
+fn add_parts(nodes: &[Node], parts: &mut Parts) {
+    for node in nodes {
+        match node {
+            Node::Literal(_) => {}
+            Node::Field(field) => field.add_parts(parts),
+            Node::Section { field, body, .. } => {
+                field.add_parts(parts);
+                add_parts(body, parts);
+            }
+        }
+    }
+}
+
+/// A template applied to a `GitInfo`.
+pub struct Rendered<'a> {
+    nodes: &'a [Node],
+    info: &'a GitInfo,
+}
+
+impl<'a> fmt::Display for Rendered<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for node in self.nodes {
+            match node {
+                Node::Literal(text) => f.write_str(text)?,
+                Node::Field(field) => write!(f, "{}", field.value(self.info))?,
+                Node::Section {
+                    field,
+                    non_zero,
+                    body,
+                } => {
+                    if field.value(self.info).is_non_zero() == *non_zero {
+                        let section = Rendered {
+                            nodes: body,
+                            info: self.info,
+                        };
+                        write!(f, "{}", section)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    /// Parses a sequence of nodes, up to the end of input or, inside a
+    /// section, up to its closing brace.
+    fn nodes(&mut self) -> anyhow::Result<Vec<Node>> {
+        let mut nodes = Vec::new();
+        let mut literal = String::new();
+        while let Some(c) = self.rest().chars().next() {
+            match c {
+                '\\' => {
+                    self.pos += 1;
+                    let escaped = self
+                        .rest()
+                        .chars()
+                        .next()
+                        .ok_or_else(|| anyhow!("trailing backslash in template"))?;
+                    literal.push(escaped);
+                    self.pos += escaped.len_utf8();
+                }
+                '{' => {
+                    if !literal.is_empty() {
+                        nodes.push(Node::Literal(std::mem::take(&mut literal)));
+                    }
+                    self.pos += 1;
+                    nodes.push(self.placeholder()?);
+                }
+                '}' if self.depth > 0 => break,
+                '}' => bail!("unmatched '}}' at offset {} in template", self.pos),
+                _ => {
+                    literal.push(c);
+                    self.pos += c.len_utf8();
+                }
+            }
+        }
+        if !literal.is_empty() {
+            nodes.push(Node::Literal(literal));
+        }
+        Ok(nodes)
+    }
+
+    /// Parses a placeholder or section, with the opening brace already
+    /// consumed.
+    fn placeholder(&mut self) -> anyhow::Result<Node> {
+        let start = self.pos;
+        let non_zero = match self.rest().chars().next() {
+            Some('?') => Some(true),
+            Some('!') => Some(false),
+            _ => None,
+        };
+        if non_zero.is_some() {
+            self.pos += 1;
+        }
+        let name_len = self
+            .rest()
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or_else(|| self.rest().len());
+        let name = &self.rest()[..name_len];
+        let field = Field::from_name(name)
+            .ok_or_else(|| anyhow!("unknown field '{}' in template", name))?;
+        self.pos += name_len;
+        let node = match non_zero {
+            None => Node::Field(field),
+            Some(non_zero) => {
+                if !self.rest().starts_with(':') {
+                    bail!(
+                        "expected ':' after '{}' at offset {} in template",
+                        name,
+                        self.pos
+                    );
+                }
+                self.pos += 1;
+                self.depth += 1;
+                let body = self.nodes()?;
+                self.depth -= 1;
+                Node::Section {
+                    field,
+                    non_zero,
+                    body,
+                }
+            }
+        };
+        if !self.rest().starts_with('}') {
+            bail!(
+                "unterminated placeholder at offset {} in template",
+                start - 1
+            );
+        }
+        self.pos += 1;
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{BranchInfo, Changes, Conflicts};
+
+    /// Returns the information for a branch `main` with the given status.
+    fn info(status: Option<Status>) -> GitInfo {
+        GitInfo {
+            head: Head::Branch {
+                branch: BranchInfo {
+                    name: "main".into(),
+                    upstream: None,
+                    push: None,
+                },
+                oid: git2::Oid::zero(),
+                short_id: "0000000".into(),
+            },
+            status,
+            dirty: None,
+            state: None,
+            stashes: 0,
+            default_branch: None,
+        }
+    }
+
+    fn status(staged: u32, changed: u32) -> Status {
+        Status {
+            staged,
+            conflicts: 0,
+            unmerged: Conflicts::default(),
+            changed,
+            untracked: Some(0),
+            index: Changes::default(),
+            worktree: Changes::default(),
+        }
+    }
+
+    fn render(template: &str, info: &GitInfo) -> String {
+        Template::parse(template).unwrap().render(info).to_string()
+    }
+
+    fn error(template: &str) -> String {
+        Template::parse(template).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn fields() {
+        let info = info(Some(status(1, 2)));
+        assert_eq!(render("{branch} +{staged} ~{changed}", &info), "main +1 ~2");
+        assert_eq!(render("", &info), "");
+    }
+
+    #[test]
+    fn escapes() {
+        let info = info(Some(status(1, 0)));
+        assert_eq!(render(r"\{staged\} \\{staged}", &info), r"{staged} \1");
+        assert_eq!(render(r"{?staged:\}\:}", &info), "}:");
+        assert_eq!(error(r"{branch}\"), "trailing backslash in template");
+    }
+
+    #[test]
+    fn sections() {
+        let template = "[{?staged:+{staged}{?changed: ~{changed}}}{!staged:{!changed:clean}}]";
+        assert_eq!(render(template, &info(Some(status(1, 2)))), "[+1 ~2]");
+        assert_eq!(render(template, &info(Some(status(1, 0)))), "[+1]");
+        assert_eq!(render(template, &info(Some(status(0, 2)))), "[]");
+        assert_eq!(render(template, &info(Some(status(0, 0)))), "[clean]");
+    }
+
+    #[test]
+    fn unknown_values() {
+        let info = info(None);
+        assert_eq!(render("{staged} {untracked}", &info), "? ?");
+        assert_eq!(render("{?staged:s}{!staged:n}", &info), "s");
+    }
+
+    #[test]
+    fn parts() {
+        let parts = Template::parse("{branch}{?dirty:*}").unwrap().parts();
+        assert!(parts.description && parts.dirty);
+        assert!(!parts.status && !parts.distance);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("{branch} {nope}"), "unknown field 'nope' in template");
+        assert_eq!(error("{?nope:x}"), "unknown field 'nope' in template");
+        assert_eq!(error("{staged}}"), "unmatched '}' at offset 8 in template");
+        assert_eq!(
+            error("ab{?staged x}"),
+            "expected ':' after 'staged' at offset 10 in template"
+        );
+        assert_eq!(
+            error("ab{staged"),
+            "unterminated placeholder at offset 2 in template"
+        );
+        assert_eq!(
+            error("{?staged:{!changed:x}"),
+            "unterminated placeholder at offset 0 in template"
+        );
+    }
+}
@@ -0,0 +1,650 @@
+//! Incremental status tracking based on inotify.
+//!
+//! A `Watcher` keeps the status entries of the last scan, and uses inotify
+//! watches on the working tree to find out which paths need to be looked at
+//! again. Only those paths are rescanned on the next query; changes to the
+//! index, `HEAD`, branches or configuration invalidate all entries, as they
+//! may affect the status of any path.
+//!
+//! Changes inside submodules and to global exclude files are not tracked;
+//! they are only picked up by the next full scan.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::common_dir;
+use crate::status::Settings;
+use crate::Status;
+
+/// Files in the git directory that affect the status of arbitrary paths.
+const GIT_DIR_FILES: &[&str] = &["HEAD", "index", "packed-refs", "config"];
+
+#[derive(Debug, Clone)]
+enum Watched {
+    /// The git directory, or the common directory of a linked worktree.
+    GitDir(PathBuf),
+    /// The `info` directory holding the `exclude` file.
+    GitInfo,
+    /// A directory below the git directory, such as `refs/heads`.
+    GitSubdir(PathBuf),
+    /// A working tree directory, relative to the working tree root.
+    Worktree(PathBuf),
+}
+
+pub struct Watcher {
+    inotify: Inotify,
+    workdir: PathBuf,
+    watches: HashMap<WatchDescriptor, Watched>,
+    /// Status of all non-current paths, or `None` if a full scan is needed.
+    entries: Option<HashMap<PathBuf, git2::Status>>,
+    /// Old and new paths of staged renames. A rename is only detected when
+    /// both paths are scanned together, so rescanning either requires a
+    /// full scan.
+    renames: Vec<PathBuf>,
+    /// Paths that changed since the last query.
+    pending: BTreeSet<PathBuf>,
+    buffer: Vec<u8>,
+}
+
+impl Watcher {
+    /// Starts watching the repository.
+    ///
+    /// Returns `Ok(None)` for bare repositories, which have no status to
+    /// track.
+    pub fn new(repo: &git2::Repository) -> anyhow::Result<Option<Self>> {
+        let workdir = match repo.workdir() {
+            Some(workdir) => workdir.to_path_buf(),
+            None => return Ok(None),
+        };
+        let mut watcher = Watcher {
+            inotify: Inotify::init()?,
+            workdir,
+            watches: HashMap::new(),
+            entries: None,
+            renames: Vec::new(),
+            pending: BTreeSet::new(),
+            buffer: vec![0; 64 * 1024],
+        };
+        let common_dir = common_dir(repo.path())?;
+        for git_dir in &[repo.path(), &common_dir] {
+            watcher.add_watch(git_dir, Watched::GitDir(git_dir.to_path_buf()))?;
+            watcher.add_watch(&git_dir.join("info"), Watched::GitInfo)?;
+        }
+        watcher.add_git_subdir(&common_dir.join("refs").join("heads"))?;
+        watcher.add_worktree_dir(repo, &repo.index()?, Path::new(""))?;
+        Ok(Some(watcher))
+    }
+
+    /// Returns the current status, rescanning only what changed since the
+    /// last call.
+    pub fn status(&mut self, repo: &git2::Repository) -> anyhow::Result<Status> {
+        self.process_events(repo)?;
+        let settings = Settings::from_repo(repo)?;
+        let mut roots = Vec::new();
+        let mut index = repo.index()?;
+        if self.pending.contains(Path::new("")) {
+            self.entries = None;
+        } else if self.entries.is_some() {
+            index.read(false)?;
+            roots = rescan_roots(&index, &self.pending);
+            let renamed = |path: &PathBuf| roots.iter().any(|root| path.starts_with(root));
+            if self.renames.iter().any(renamed) {
+                self.entries = None;
+            }
+        }
+        let entries = match self.entries.take() {
+            Some(mut entries) => {
+                if !roots.is_empty() {
+                    rescan(
+                        repo,
+                        &settings,
+                        &index,
+                        &mut entries,
+                        &mut self.renames,
+                        &roots,
+                    )?;
+                }
+                entries
+            }
+            None => {
+                self.renames.clear();
+                scan(repo, &settings, settings.options(), &mut self.renames)?
+            }
+        };
+        self.pending.clear();
+        let status = Status::from_entries(entries.values().copied()).classify_conflicts(repo)?;
+        self.entries = Some(entries);
+        Ok(status)
+    }
+
+    fn process_events(&mut self, repo: &git2::Repository) -> anyhow::Result<()> {
+        loop {
+            // Without pending events, this yields an empty iterator.
+            let events: Vec<_> = self
+                .inotify
+                .read_events(&mut self.buffer)?
+                .map(|event| event.into_owned())
+                .collect();
+            if events.is_empty() {
+                return Ok(());
+            }
+            // Directories whose subtrees need to be walked for directories
+            // to watch, as they were created, or may no longer be ignored.
+            let mut walk = Vec::new();
+            let mut index_changed = false;
+            for event in events {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    self.entries = None;
+                    continue;
+                }
+                if event.mask.contains(EventMask::IGNORED) {
+                    self.watches.remove(&event.wd);
+                    continue;
+                }
+                let (watched, name) = match (self.watches.get(&event.wd), event.name) {
+                    (Some(watched), Some(name)) => (watched.clone(), name),
+                    _ => continue,
+                };
+                let is_new_dir = event.mask.contains(EventMask::ISDIR)
+                    && event
+                        .mask
+                        .intersects(EventMask::CREATE | EventMask::MOVED_TO);
+                match watched {
+                    Watched::GitDir(git_dir) => {
+                        if is_new_dir && name == OsStr::new("info") {
+                            self.add_watch(&git_dir.join(&name), Watched::GitInfo)?;
+                        }
+                        if GIT_DIR_FILES.iter().any(|file| name == OsStr::new(file)) {
+                            self.entries = None;
+                            index_changed |= name == OsStr::new("index");
+                        }
+                    }
+                    Watched::GitInfo => {
+                        if name == OsStr::new("exclude") {
+                            walk.push(PathBuf::new());
+                        }
+                        if !is_lock_file(&name) {
+                            self.entries = None;
+                        }
+                    }
+                    Watched::GitSubdir(dir) => {
+                        if is_new_dir {
+                            self.add_git_subdir(&dir.join(&name))?;
+                        }
+                        if !is_lock_file(&name) {
+                            self.entries = None;
+                        }
+                    }
+                    Watched::Worktree(dir) => {
+                        if name == OsStr::new(".git") {
+                            continue;
+                        }
+                        let path = dir.join(&name);
+                        if is_new_dir {
+                            walk.push(path.clone());
+                        }
+                        if name == OsStr::new(".gitignore") {
+                            // This may change the status of anything below `dir`.
+                            walk.push(dir.clone());
+                            self.pending.insert(dir);
+                        } else {
+                            self.pending.insert(path);
+                        }
+                    }
+                }
+            }
+            if walk.is_empty() && !index_changed {
+                continue;
+            }
+            let mut index = repo.index()?;
+            index.read(false)?;
+            if index_changed {
+                // Ignored directories are watched once files in them are
+                // added to the index.
+                walk.extend(self.unwatched_tracked_dirs(&index));
+            }
+            for dir in walk {
+                self.add_worktree_dir(repo, &index, &dir)?;
+            }
+        }
+    }
+
+    /// Returns the topmost directories containing tracked files that are
+    /// not watched.
+    fn unwatched_tracked_dirs(&self, index: &git2::Index) -> BTreeSet<PathBuf> {
+        let watched: HashSet<&Path> = self
+            .watches
+            .values()
+            .filter_map(|watched| match watched {
+                Watched::Worktree(dir) => Some(dir.as_path()),
+                _ => None,
+            })
+            .collect();
+        let mut dirs = BTreeSet::new();
+        for entry in index.iter() {
+            let path = Path::new(OsStr::from_bytes(&entry.path));
+            let unwatched = path
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| !watched.contains(dir))
+                .last();
+            dirs.extend(unwatched.map(Path::to_path_buf));
+        }
+        dirs
+    }
+
+    /// Adds a watch, returning `false` if `path` is not a directory
+    /// (anymore).
+    fn add_watch(&mut self, path: &Path, watched: Watched) -> anyhow::Result<bool> {
+        let mask = WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MODIFY
+            | WatchMask::ATTRIB
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::DONT_FOLLOW
+            | WatchMask::ONLYDIR
+            | WatchMask::EXCL_UNLINK;
+        match self.inotify.add_watch(path, mask) {
+            Ok(wd) => {
+                self.watches.insert(wd, watched);
+                Ok(true)
+            }
+            Err(e) if is_vanished(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Watches the git directory `dir` and its subdirectories.
+    fn add_git_subdir(&mut self, dir: &Path) -> anyhow::Result<()> {
+        let mut stack = vec![dir.to_path_buf()];
+        while let Some(dir) = stack.pop() {
+            if !self.add_watch(&dir, Watched::GitSubdir(dir.clone()))? {
+                continue;
+            }
+            stack.extend(subdirs(&dir)?);
+        }
+        Ok(())
+    }
+
+    /// Watches the working tree directory `dir` and its subdirectories,
+    /// skipping ignored directories without tracked files.
+    fn add_worktree_dir(
+        &mut self,
+        repo: &git2::Repository,
+        index: &git2::Index,
+        dir: &Path,
+    ) -> anyhow::Result<()> {
+        let mut stack = vec![dir.to_path_buf()];
+        while let Some(dir) = stack.pop() {
+            if dir.file_name() == Some(OsStr::new(".git"))
+                || (!contains_tracked(index, &dir) && is_ignored_dir(repo, &dir)?)
+            {
+                continue;
+            }
+            let path = self.workdir.join(&dir);
+            if !self.add_watch(&path, Watched::Worktree(dir.clone()))? {
+                continue;
+            }
+            for subdir in subdirs(&path)? {
+                stack.push(dir.join(subdir.file_name().unwrap_or_default()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Scans the paths selected by `options`, adding the paths involved in
+/// staged renames to `renames`.
+fn scan(
+    repo: &git2::Repository,
+    settings: &Settings,
+    mut options: git2::StatusOptions,
+    renames: &mut Vec<PathBuf>,
+) -> anyhow::Result<HashMap<PathBuf, git2::Status>> {
+    let statuses = repo.statuses(Some(&mut options))?;
+    let mut entries = HashMap::new();
+    for entry in statuses.iter() {
+        let mut path = Path::new(OsStr::from_bytes(entry.path_bytes()));
+        if let Some(delta) = entry.head_to_index() {
+            if let (git2::Delta::Renamed, Some(old), Some(new)) = (
+                delta.status(),
+                delta.old_file().path(),
+                delta.new_file().path(),
+            ) {
+                renames.extend([old.to_path_buf(), new.to_path_buf()]);
+                // The entry's path is the old one, which may be taken by
+                // an untracked file by now.
+                path = new;
+            }
+        }
+        entries.insert(path.to_path_buf(), settings.entry_status(repo, &entry)?);
+    }
+    Ok(entries)
+}
+
+/// Updates `entries` with a rescan of `roots` and everything below them.
+///
+/// None of the `renames` may be below `roots`.
+fn rescan(
+    repo: &git2::Repository,
+    settings: &Settings,
+    index: &git2::Index,
+    entries: &mut HashMap<PathBuf, git2::Status>,
+    renames: &mut Vec<PathBuf>,
+    roots: &[PathBuf],
+) -> anyhow::Result<()> {
+    entries.retain(|path, _| !roots.iter().any(|root| path.starts_with(root)));
+    let workdir = repo.workdir().unwrap_or_else(|| repo.path());
+    let (dirs, files): (Vec<_>, Vec<_>) = roots
+        .iter()
+        .partition(|root| fs::symlink_metadata(workdir.join(root)).is_ok_and(|m| m.is_dir()));
+    let (untracked_dirs, dirs): (Vec<_>, Vec<_>) = dirs
+        .into_iter()
+        .partition(|dir| !contains_tracked(index, dir));
+    // An untracked file matched by a pathspec is only reported when recursing
+    // into untracked directories. Directories must be scanned without
+    // recursion, however, so untracked directories are reported as a single
+    // entry, just like in a full scan, unless configured otherwise.
+    let recurse_dirs = settings.recurse_untracked_dirs();
+    for (paths, recurse) in &[(dirs, recurse_dirs), (files, true)] {
+        if !paths.is_empty() {
+            entries.extend(scan_paths(repo, settings, paths, *recurse, renames)?);
+        }
+    }
+    if untracked_dirs.is_empty() {
+        return Ok(());
+    }
+    // A pathspec naming an untracked directory doesn't match the entry for
+    // the directory itself, so its contents are scanned, and reported as a
+    // single entry unless configured otherwise.
+    let scanned = scan_paths(repo, settings, &untracked_dirs, true, renames)?;
+    for (path, status) in scanned {
+        match untracked_dirs.iter().find(|dir| path.starts_with(dir)) {
+            Some(dir) if !recurse_dirs && status == git2::Status::WT_NEW => {
+                entries.insert(dir.to_path_buf(), status);
+            }
+            _ => {
+                entries.insert(path, status);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Scans `paths` and everything below them, recursing into untracked
+/// directories if `recurse` is set.
+fn scan_paths(
+    repo: &git2::Repository,
+    settings: &Settings,
+    paths: &[&PathBuf],
+    recurse: bool,
+    renames: &mut Vec<PathBuf>,
+) -> anyhow::Result<HashMap<PathBuf, git2::Status>> {
+    let mut options = settings.options();
+    for path in paths {
+        options.pathspec(escape_pathspec(path));
+    }
+    options.recurse_untracked_dirs(recurse);
+    scan(repo, settings, options, renames)
+}
+
+/// Determines the minimal set of paths to rescan for the changed `paths`.
+///
+/// An untracked directory is reported as a single entry, so a change below
+/// such a directory requires rescanning the whole directory.
+fn rescan_roots(index: &git2::Index, paths: &BTreeSet<PathBuf>) -> Vec<PathBuf> {
+    let mut roots: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        let root = path
+            .ancestors()
+            .skip(1)
+            .take_while(|ancestor| {
+                !ancestor.as_os_str().is_empty() && !contains_tracked(index, ancestor)
+            })
+            .last()
+            .unwrap_or(path);
+        if !roots.iter().any(|r| root.starts_with(r)) {
+            roots.retain(|r| !r.starts_with(root));
+            roots.push(root.to_path_buf());
+        }
+    }
+    roots
+}
+
+/// Checks whether there are index entries below the directory `dir`.
+fn contains_tracked(index: &git2::Index, dir: &Path) -> bool {
+    let mut prefix = dir.as_os_str().as_bytes().to_vec();
+    prefix.push(b'/');
+    index.find_prefix(prefix).is_ok()
+}
+
+/// Escapes `path` for use as a literal pathspec.
+fn escape_pathspec(path: &Path) -> Vec<u8> {
+    let mut escaped = Vec::new();
+    for &byte in path.as_os_str().as_bytes() {
+        if matches!(byte, b'\\' | b'*' | b'?' | b'[') {
+            escaped.push(b'\\');
+        }
+        escaped.push(byte);
+    }
+    escaped
+}
+
+fn subdirs(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if is_vanished(&e) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        // `file_type` does not follow symlinks, so we won't leave the tree.
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+fn is_ignored_dir(repo: &git2::Repository, dir: &Path) -> anyhow::Result<bool> {
+    if dir.as_os_str().is_empty() {
+        return Ok(false);
+    }
+    // A trailing slash is needed for directory-only patterns to apply.
+    let mut path = dir.as_os_str().to_owned();
+    path.push("/");
+    Ok(repo.is_path_ignored(Path::new(&path))?)
+}
+
+fn is_lock_file(name: &OsStr) -> bool {
+    name.as_bytes().ends_with(b".lock")
+}
+
+fn is_vanished(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_repo::TestRepo;
+
+    /// Tracked files at several depths.
+    const TRACKED: &[&str] = &["a", "d/b", "d/e/c", "d/e/f/g"];
+
+    /// A repository with a watcher that has done its initial scan.
+    struct Fixture {
+        repo: TestRepo,
+        watcher: Watcher,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let repo = TestRepo::with_files(TRACKED);
+            let watcher = Watcher::new(&repo.repo).unwrap().unwrap();
+            let mut fixture = Fixture { repo, watcher };
+            fixture.check("initial scan");
+            fixture
+        }
+
+        fn write(&self, name: &str) {
+            self.repo.write(name, "changed");
+        }
+
+        fn rename(&self, from: &str, to: &str) {
+            fs::rename(self.repo.path(from), self.repo.path(to)).unwrap();
+        }
+
+        /// Checks that the watcher agrees with a full scan.
+        fn check(&mut self, step: &str) {
+            let expected = Status::from_repo(&self.repo.repo).unwrap();
+            let status = self.watcher.status(&self.repo.repo).unwrap();
+            assert_eq!(status, expected, "after {}", step);
+            // Once more, with nothing having changed in between.
+            let status = self.watcher.status(&self.repo.repo).unwrap();
+            assert_eq!(status, expected, "after {}, unchanged", step);
+        }
+    }
+
+    #[test]
+    fn create_files() {
+        let mut fixture = Fixture::new();
+        for name in &["x", "d/x", "d/e/x", "d/e/f/x"] {
+            fixture.write(name);
+            fixture.check(&format!("creating {}", name));
+        }
+    }
+
+    #[test]
+    fn create_untracked_dirs() {
+        let mut fixture = Fixture::new();
+        for name in &["u/x", "d/u/x", "d/u/v/x", "d/e/u/x", "d/e/f/u/v/x"] {
+            fixture.write(name);
+            fixture.check(&format!("creating {}", name));
+        }
+        fs::create_dir(fixture.repo.path("d/empty")).unwrap();
+        fixture.check("creating an empty directory");
+        fixture.write("d/empty/x");
+        fixture.check("creating a file in the empty directory");
+    }
+
+    #[test]
+    fn create_untracked_dirs_showing_all_files() {
+        let mut fixture = Fixture::new();
+        let mut config = fixture.repo.repo.config().unwrap();
+        config.set_str("status.showUntrackedFiles", "all").unwrap();
+        fixture.check("configuring status.showUntrackedFiles");
+        for name in &["u/x", "d/u/x", "d/u/v/x", "d/e/f/u/v/x"] {
+            fixture.write(name);
+            fixture.check(&format!("creating {}", name));
+        }
+    }
+
+    #[test]
+    fn modify_files() {
+        let mut fixture = Fixture::new();
+        for name in TRACKED {
+            fixture.write(name);
+            fixture.check(&format!("modifying {}", name));
+        }
+        fixture.write("d/u/x");
+        fixture.check("creating d/u/x");
+        fixture.write("d/u/x");
+        fixture.check("modifying d/u/x");
+    }
+
+    #[test]
+    fn rename() {
+        let mut fixture = Fixture::new();
+        fixture.rename("a", "d/e/a");
+        fixture.check("moving a file down");
+        fixture.rename("d/e/f/g", "g");
+        fixture.check("moving a file up");
+        fixture.rename("d/e", "d/e2");
+        fixture.check("renaming a tracked directory");
+        fixture.write("d/u/v/x");
+        fixture.check("creating d/u/v/x");
+        fixture.rename("d/u", "u");
+        fixture.check("moving an untracked directory up");
+        fixture.rename("u", "d/e2/u");
+        fixture.check("moving an untracked directory down");
+        fixture.rename("d/e2/u/v", "d/v");
+        fixture.check("moving a nested untracked directory");
+    }
+
+    #[test]
+    fn delete() {
+        let mut fixture = Fixture::new();
+        fs::remove_file(fixture.repo.path("a")).unwrap();
+        fixture.check("deleting a");
+        fs::remove_file(fixture.repo.path("d/e/f/g")).unwrap();
+        fixture.check("deleting d/e/f/g");
+        fixture.write("d/u/v/x");
+        fixture.check("creating d/u/v/x");
+        fs::remove_dir_all(fixture.repo.path("d/u/v")).unwrap();
+        fixture.check("deleting d/u/v");
+        fs::remove_dir_all(fixture.repo.path("d/u")).unwrap();
+        fixture.check("deleting d/u");
+        fs::remove_dir_all(fixture.repo.path("d/e")).unwrap();
+        fixture.check("deleting d/e");
+        fs::remove_dir_all(fixture.repo.path("d")).unwrap();
+        fixture.check("deleting d");
+    }
+
+    #[test]
+    fn tracked_files_in_ignored_dirs() {
+        let mut fixture = Fixture::new();
+        fs::write(fixture.repo.path(".gitignore"), "ign/\n").unwrap();
+        fixture.write("ign/f");
+        fixture.write("ign/sub/f");
+        fixture.check("creating the ignored directory");
+        let mut index = fixture.repo.repo.index().unwrap();
+        index.add_path(Path::new("ign/f")).unwrap();
+        index.add_path(Path::new("ign/sub/f")).unwrap();
+        index.write().unwrap();
+        fixture.check("adding files in the ignored directory");
+        for name in &["ign/f", "ign/sub/f"] {
+            fixture.write(name);
+            fixture.check(&format!("modifying {}", name));
+        }
+        fixture.watcher = Watcher::new(&fixture.repo.repo).unwrap().unwrap();
+        fixture.check("starting over");
+        fs::remove_file(fixture.repo.path("ign/sub/f")).unwrap();
+        fixture.check("deleting ign/sub/f");
+    }
+
+    #[test]
+    fn unignored_dirs() {
+        let mut fixture = Fixture::new();
+        let mut config = fixture.repo.repo.config().unwrap();
+        config.set_str("status.showUntrackedFiles", "all").unwrap();
+        let exclude = fixture.repo.repo.path().join("info").join("exclude");
+        fs::create_dir_all(exclude.parent().unwrap()).unwrap();
+        for ignore in &[fixture.repo.path(".gitignore"), exclude] {
+            fs::write(ignore, "ign/\n").unwrap();
+            fixture.write("ign/x");
+            fixture.write("ign/sub/x");
+            fixture.check("creating an ignored directory");
+            fs::write(ignore, "").unwrap();
+            fixture.check("no longer ignoring it");
+            for name in &["ign/y", "ign/sub/y"] {
+                fixture.write(name);
+                fixture.check(&format!("creating {}", name));
+            }
+            fs::remove_dir_all(fixture.repo.path("ign")).unwrap();
+            fixture.check("deleting it");
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! A throwaway repository, shared by the integration tests, and the unit
+//! tests of the library, which include this file as `test_repo`.
+
+// Not every test uses every helper.
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tempfile::TempDir;
+
+pub struct TestRepo {
+    pub dir: TempDir,
+    pub repo: git2::Repository,
+}
+
+impl TestRepo {
+    /// Creates a repository without any commits, or index file.
+    pub fn unborn() -> Self {
+        let dir = TempDir::new().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        TestRepo { dir, repo }
+    }
+
+    /// Creates a repository with a single commit containing the files
+    /// `names`, each containing its own name.
+    pub fn with_files(names: &[&str]) -> Self {
+        let repo = Self::unborn();
+        for name in names {
+            repo.write(name, name);
+            repo.stage(name);
+        }
+        repo.commit("initial");
+        repo
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    /// Writes `contents` to `name`, creating its parent directories.
+    pub fn write(&self, name: &str, contents: &str) {
+        let path = self.path(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    pub fn remove(&self, name: &str) {
+        fs::remove_file(self.path(name)).unwrap();
+    }
+
+    #[cfg(unix)]
+    pub fn symlink(&self, name: &str, target: &str) {
+        std::os::unix::fs::symlink(target, self.path(name)).unwrap();
+    }
+
+    /// Adds `name` to the index, or removes it if it does not exist.
+    pub fn stage(&self, name: &str) {
+        let mut index = self.repo.index().unwrap();
+        if self.path(name).symlink_metadata().is_ok() {
+            index.add_path(Path::new(name)).unwrap();
+        } else {
+            index.remove_path(Path::new(name)).unwrap();
+        }
+        index.write().unwrap();
+    }
+
+    /// Commits the index on top of `HEAD`, and updates `HEAD`.
+    pub fn commit(&self, message: &str) -> git2::Oid {
+        self.commit_to(Some("HEAD"), message)
+    }
+
+    /// Commits the index on top of `HEAD`, updating `update_ref`, if any.
+    pub fn commit_to(&self, update_ref: Option<&str>, message: &str) -> git2::Oid {
+        let mut index = self.repo.index().unwrap();
+        let tree = self.repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+        let parent = self
+            .repo
+            .head()
+            .ok()
+            .map(|head| head.peel_to_commit().unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+        self.repo
+            .commit(update_ref, &signature, &signature, message, &tree, &parents)
+            .unwrap()
+    }
+}
@@ -0,0 +1,352 @@
+//! Regression tests for the classification of status entries.
+
+mod common;
+
+use std::fs;
+
+use common::TestRepo as Repo;
+use gitstat::{Changes, Conflicts, Dirty, Status};
+
+impl Repo {
+    /// Creates a repository with a single commit containing the files `a`,
+    /// `b` and `c`.
+    fn new() -> Self {
+        Self::with_files(&["a", "b", "c"])
+    }
+
+    /// Returns the status, checking that the dirty-only mode agrees.
+    fn status(&self) -> Status {
+        let status = Status::from_repo(&self.repo).unwrap();
+        let dirty = Dirty::from_repo(&self.repo).unwrap();
+        assert_eq!(dirty, Dirty::from_status(&status));
+        status
+    }
+}
+
+/// Builds the expected status from the legacy counts and the breakdowns.
+fn status(
+    (staged, conflicts, changed, untracked): (u32, u32, u32, u32),
+    index: Changes,
+    worktree: Changes,
+) -> Status {
+    Status {
+        staged,
+        conflicts,
+        unmerged: Conflicts::default(),
+        changed,
+        untracked: Some(untracked),
+        index,
+        worktree,
+    }
+}
+
+fn none() -> Changes {
+    Changes::default()
+}
+
+fn added() -> Changes {
+    Changes {
+        added: 1,
+        ..Changes::default()
+    }
+}
+
+fn modified() -> Changes {
+    Changes {
+        modified: 1,
+        ..Changes::default()
+    }
+}
+
+fn deleted() -> Changes {
+    Changes {
+        deleted: 1,
+        ..Changes::default()
+    }
+}
+
+fn renamed() -> Changes {
+    Changes {
+        renamed: 1,
+        ..Changes::default()
+    }
+}
+
+fn typechanged() -> Changes {
+    Changes {
+        typechanged: 1,
+        ..Changes::default()
+    }
+}
+
+#[test]
+fn clean() {
+    let repo = Repo::new();
+    assert_eq!(repo.status(), status((0, 0, 0, 0), none(), none()));
+    assert!(repo.status().is_clean());
+}
+
+#[test]
+fn index_new() {
+    let repo = Repo::new();
+    repo.write("new", "new");
+    repo.stage("new");
+    assert_eq!(repo.status(), status((1, 0, 0, 0), added(), none()));
+}
+
+#[test]
+fn index_modified() {
+    let repo = Repo::new();
+    repo.write("a", "changed");
+    repo.stage("a");
+    assert_eq!(repo.status(), status((1, 0, 0, 0), modified(), none()));
+}
+
+#[test]
+fn index_deleted() {
+    let repo = Repo::new();
+    repo.remove("a");
+    repo.stage("a");
+    assert_eq!(repo.status(), status((1, 0, 0, 0), deleted(), none()));
+}
+
+#[test]
+fn index_renamed() {
+    let repo = Repo::new();
+    fs::rename(repo.path("a"), repo.path("renamed")).unwrap();
+    repo.stage("a");
+    repo.stage("renamed");
+    assert_eq!(repo.status(), status((1, 0, 0, 0), renamed(), none()));
+}
+
+#[cfg(unix)]
+#[test]
+fn index_typechange() {
+    let repo = Repo::new();
+    repo.remove("a");
+    repo.symlink("a", "b");
+    repo.stage("a");
+    assert_eq!(repo.status(), status((1, 0, 0, 0), typechanged(), none()));
+}
+
+#[test]
+fn wt_new() {
+    let repo = Repo::new();
+    repo.write("new", "new");
+    assert_eq!(repo.status(), status((0, 0, 0, 1), none(), added()));
+}
+
+#[test]
+fn wt_new_unborn() {
+    let repo = Repo::unborn();
+    repo.write("new", "new");
+    assert_eq!(repo.status(), status((0, 0, 0, 1), none(), added()));
+}
+
+#[test]
+fn wt_new_directory() {
+    let repo = Repo::new();
+    fs::create_dir(repo.path("dir")).unwrap();
+    repo.write("dir/x", "x");
+    repo.write("dir/y", "y");
+    // Like with `git status`, an untracked directory counts as one entry.
+    assert_eq!(repo.status(), status((0, 0, 0, 1), none(), added()));
+}
+
+#[test]
+fn wt_modified() {
+    let repo = Repo::new();
+    repo.write("a", "changed");
+    assert_eq!(repo.status(), status((0, 0, 1, 0), none(), modified()));
+}
+
+#[test]
+fn wt_deleted() {
+    let repo = Repo::new();
+    repo.remove("a");
+    assert_eq!(repo.status(), status((0, 0, 1, 0), none(), deleted()));
+}
+
+#[cfg(unix)]
+#[test]
+fn wt_typechange() {
+    let repo = Repo::new();
+    repo.remove("a");
+    repo.symlink("a", "b");
+    assert_eq!(repo.status(), status((0, 0, 1, 0), none(), typechanged()));
+}
+
+#[test]
+fn show_untracked_no() {
+    let repo = Repo::new();
+    repo.repo
+        .config()
+        .unwrap()
+        .set_str("status.showUntrackedFiles", "no")
+        .unwrap();
+    repo.write("new", "new");
+    assert_eq!(repo.status(), status((0, 0, 0, 0), none(), none()));
+}
+
+#[test]
+fn show_untracked_all() {
+    let repo = Repo::new();
+    repo.repo
+        .config()
+        .unwrap()
+        .set_str("status.showUntrackedFiles", "all")
+        .unwrap();
+    fs::create_dir(repo.path("dir")).unwrap();
+    repo.write("dir/x", "x");
+    repo.write("dir/y", "y");
+    let worktree = Changes {
+        added: 2,
+        ..Changes::default()
+    };
+    assert_eq!(repo.status(), status((0, 0, 0, 2), none(), worktree));
+}
+
+#[test]
+fn ignored() {
+    let repo = Repo::new();
+    repo.write(".gitignore", "*.log\n");
+    repo.stage(".gitignore");
+    repo.commit("ignore logs");
+    repo.write("debug.log", "log");
+    assert_eq!(repo.status(), status((0, 0, 0, 0), none(), none()));
+}
+
+#[test]
+fn staged_and_modified() {
+    let repo = Repo::new();
+    repo.write("new", "new");
+    repo.stage("new");
+    repo.write("new", "newer");
+    assert_eq!(repo.status(), status((1, 0, 1, 0), added(), modified()));
+}
+
+impl Repo {
+    /// Creates diverging commits changing `name` to `ours` and `theirs`,
+    /// where `None` deletes it, and merges the latter into the former.
+    fn merge_conflict(&self, name: &str, ours: Option<&str>, theirs: Option<&str>) {
+        let base = self.repo.head().unwrap().peel_to_commit().unwrap();
+        let change = |contents: Option<&str>, message| {
+            match contents {
+                Some(contents) => self.write(name, contents),
+                None => self.remove(name),
+            }
+            self.stage(name);
+            self.commit(message)
+        };
+        let ours = change(ours, "ours");
+        let ours = self.repo.find_commit(ours).unwrap();
+        self.repo
+            .reset(base.as_object(), git2::ResetType::Hard, None)
+            .unwrap();
+        let theirs = change(theirs, "theirs");
+        self.repo
+            .reset(ours.as_object(), git2::ResetType::Hard, None)
+            .unwrap();
+        let theirs = self.repo.find_annotated_commit(theirs).unwrap();
+        self.repo.merge(&[&theirs], None, None).unwrap();
+    }
+}
+
+#[test]
+fn conflicted() {
+    let repo = Repo::new();
+    repo.merge_conflict("a", Some("ours"), Some("theirs"));
+    let expected = Status {
+        unmerged: Conflicts {
+            both_modified: 1,
+            ..Conflicts::default()
+        },
+        ..status((0, 1, 0, 0), none(), none())
+    };
+    assert_eq!(repo.status(), expected);
+}
+
+#[test]
+fn conflicted_deleted_by_them() {
+    let repo = Repo::new();
+    repo.merge_conflict("a", Some("ours"), None);
+    let status = repo.status();
+    assert_eq!(status.conflicts, 1);
+    assert_eq!(
+        status.unmerged,
+        Conflicts {
+            deleted_by_them: 1,
+            ..Conflicts::default()
+        }
+    );
+}
+
+#[test]
+fn conflicted_deleted_by_us() {
+    let repo = Repo::new();
+    repo.merge_conflict("a", None, Some("theirs"));
+    let status = repo.status();
+    assert_eq!(status.conflicts, 1);
+    assert_eq!(
+        status.unmerged,
+        Conflicts {
+            deleted_by_us: 1,
+            ..Conflicts::default()
+        }
+    );
+}
+
+/// Covers the flags that can't easily be produced in a repository, as well
+/// as each flag on its own.
+#[test]
+fn every_flag() {
+    use git2::Status as S;
+    let cases = [
+        (S::CURRENT, (0, 0, 0, 0)),
+        (S::INDEX_NEW, (1, 0, 0, 0)),
+        (S::INDEX_MODIFIED, (1, 0, 0, 0)),
+        (S::INDEX_DELETED, (1, 0, 0, 0)),
+        (S::INDEX_RENAMED, (1, 0, 0, 0)),
+        (S::INDEX_TYPECHANGE, (1, 0, 0, 0)),
+        (S::WT_NEW, (0, 0, 0, 1)),
+        (S::WT_MODIFIED, (0, 0, 1, 0)),
+        (S::WT_DELETED, (0, 0, 1, 0)),
+        (S::WT_TYPECHANGE, (0, 0, 1, 0)),
+        (S::WT_RENAMED, (0, 0, 1, 0)),
+        (S::IGNORED, (0, 0, 0, 0)),
+        (S::CONFLICTED, (0, 1, 0, 0)),
+    ];
+    for (flag, (staged, conflicts, changed, untracked)) in cases {
+        let status = Status::from_entries([flag]);
+        let counts = (
+            status.staged,
+            status.conflicts,
+            status.changed,
+            status.untracked,
+        );
+        assert_eq!(
+            counts,
+            (staged, conflicts, changed, Some(untracked)),
+            "{:?}",
+            flag
+        );
+    }
+}
+
+#[test]
+fn line_counts() {
+    let repo = Repo::new();
+    repo.write("a", "a\nstaged\n");
+    repo.stage("a");
+    repo.write("a", "unstaged\n");
+    let status = repo.status().count_lines(&repo.repo).unwrap();
+    assert_eq!(
+        (status.index.insertions, status.index.deletions),
+        (Some(2), Some(1))
+    );
+    assert_eq!(
+        (status.worktree.insertions, status.worktree.deletions),
+        (Some(1), Some(2))
+    );
+    assert_eq!(repo.status().index.insertions, None);
+}
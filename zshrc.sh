@@ -38,7 +38,7 @@ function chpwd_update_git_vars() {
 function update_current_git_vars() {
     unset __CURRENT_GIT_STATUS
 
-    _GIT_STATUS=`${GITSTAT_COMMAND} 2>/dev/null`
+    _GIT_STATUS=`${=GITSTAT_COMMAND} 2>/dev/null`
     __CURRENT_GIT_STATUS=("${(@s: :)_GIT_STATUS}")
     GIT_BRANCH=$__CURRENT_GIT_STATUS[1]
     GIT_AHEAD=$__CURRENT_GIT_STATUS[2]
@@ -47,32 +47,36 @@ function update_current_git_vars() {
     GIT_CONFLICTS=$__CURRENT_GIT_STATUS[5]
     GIT_CHANGED=$__CURRENT_GIT_STATUS[6]
     GIT_UNTRACKED=$__CURRENT_GIT_STATUS[7]
+    GIT_STASHES=$__CURRENT_GIT_STATUS[8]
 }
 
 git_super_status() {
     precmd_update_git_vars
     if [ -n "$__CURRENT_GIT_STATUS" ]; then
         STATUS="$ZSH_THEME_GIT_PROMPT_PREFIX$ZSH_THEME_GIT_PROMPT_BRANCH$GIT_BRANCH%{${reset_color}%}"
-        if [ "$GIT_BEHIND" -ne "0" ]; then
+        if [ "$GIT_BEHIND" != "0" ]; then
             STATUS="$STATUS$ZSH_THEME_GIT_PROMPT_BEHIND$GIT_BEHIND%{${reset_color}%}"
         fi
-        if [ "$GIT_AHEAD" -ne "0" ]; then
+        if [ "$GIT_AHEAD" != "0" ]; then
             STATUS="$STATUS$ZSH_THEME_GIT_PROMPT_AHEAD$GIT_AHEAD%{${reset_color}%}"
         fi
         STATUS="$STATUS$ZSH_THEME_GIT_PROMPT_SEPARATOR"
-        if [ "$GIT_STAGED" -ne "0" ]; then
+        if [ "$GIT_STAGED" != "0" ]; then
             STATUS="$STATUS$ZSH_THEME_GIT_PROMPT_STAGED$GIT_STAGED%{${reset_color}%}"
         fi
-        if [ "$GIT_CONFLICTS" -ne "0" ]; then
+        if [ "$GIT_CONFLICTS" != "0" ]; then
             STATUS="$STATUS$ZSH_THEME_GIT_PROMPT_CONFLICTS$GIT_CONFLICTS%{${reset_color}%}"
         fi
-        if [ "$GIT_CHANGED" -ne "0" ]; then
+        if [ "$GIT_CHANGED" != "0" ]; then
             STATUS="$STATUS$ZSH_THEME_GIT_PROMPT_CHANGED$GIT_CHANGED%{${reset_color}%}"
         fi
-        if [ "$GIT_UNTRACKED" -ne "0" ]; then
+        if [ "$GIT_UNTRACKED" != "0" ]; then
             STATUS="$STATUS$ZSH_THEME_GIT_PROMPT_UNTRACKED%{${reset_color}%}"
         fi
-        if [ "$GIT_CHANGED" -eq "0" ] && [ "$GIT_CONFLICTS" -eq "0" ] && [ "$GIT_STAGED" -eq "0" ] && [ "$GIT_UNTRACKED" -eq "0" ]; then
+        if [ -n "$GIT_STASHES" ] && [ "$GIT_STASHES" != "0" ]; then
+            STATUS="$STATUS$ZSH_THEME_GIT_PROMPT_STASHED$GIT_STASHES%{${reset_color}%}"
+        fi
+        if [ "$GIT_CHANGED" = "0" ] && [ "$GIT_CONFLICTS" = "0" ] && [ "$GIT_STAGED" = "0" ] && [ "$GIT_UNTRACKED" = "0" ]; then
             STATUS="$STATUS$ZSH_THEME_GIT_PROMPT_CLEAN"
         fi
         STATUS="$STATUS%{${reset_color}%}$ZSH_THEME_GIT_PROMPT_SUFFIX"
@@ -91,4 +95,5 @@ ZSH_THEME_GIT_PROMPT_CHANGED="%{$fg[blue]%}%{✚%G%}"
 ZSH_THEME_GIT_PROMPT_BEHIND="%{↓%G%}"
 ZSH_THEME_GIT_PROMPT_AHEAD="%{↑%G%}"
 ZSH_THEME_GIT_PROMPT_UNTRACKED="%{…%G%}"
+ZSH_THEME_GIT_PROMPT_STASHED="%{$fg_bold[blue]%}%{⚑%G%}"
 ZSH_THEME_GIT_PROMPT_CLEAN="%{$fg_bold[green]%}%{✔%G%}"